@@ -0,0 +1,207 @@
+//! Async packet I/O over `embedded_io_async`, e.g. for Embassy firmware. Enabled by the `async`
+//! feature.
+//!
+//! Packets are written and read by the same `PacketEncoder` and `PacketDecoder` as are used
+//! for non-blocking I/O, so the wire format is identical to that of the blocking functions.
+
+use embedded_io_async::{Read, Write};
+
+use crate::crc::{Checksum, Crc32};
+use crate::{Error, Packet, PacketDecoder, PacketEncoder, Raw, Version, WireFormat};
+
+/// Number of bytes handed to the device at a time when writing
+const CHUNK_LEN: usize = 16;
+
+impl Packet<Raw> {
+    /// Read in a raw packet of the default capacity, using the default wire format and header
+    /// version
+    pub async fn read_async<S: Read>(s: S) -> Result<Self, Error<S::Error>> {
+        Self::read_async_sized(s).await
+    }
+}
+
+impl<const N: usize> Packet<Raw<N>, N> {
+    /// Write out a raw packet to the stream, using the default wire format and header version
+    pub async fn write_async<S: Write>(&self, s: S) -> Result<(), Error<S::Error>> {
+        self.write_async_with(
+            s,
+            WireFormat::default(),
+            Version::default(),
+            Crc32::default(),
+        )
+        .await
+    }
+
+    /// Write out a raw packet to the stream, using the given wire format, header version and
+    /// checksum
+    pub async fn write_async_with<S: Write, K: Checksum>(
+        &self,
+        mut s: S,
+        format: WireFormat,
+        version: Version,
+        checksum: K,
+    ) -> Result<(), Error<S::Error>> {
+        let mut encoder = PacketEncoder::<N>::with_format_sized(format)
+            .with_version(version)
+            .with_checksum(checksum);
+        // A new encoder is idle, so never blocks
+        nb::block!(encoder.encode(self)).map_err(Error::widen)?;
+
+        let mut chunk = [0; CHUNK_LEN];
+        loop {
+            let n = encoder.fill(&mut chunk);
+            if n == 0 {
+                return Ok(());
+            }
+            s.write_all(&chunk[..n]).await.map_err(Error::Serial)?;
+        }
+    }
+
+    /// As `Packet::read_async`, for packets of any capacity
+    pub async fn read_async_sized<S: Read>(s: S) -> Result<Self, Error<S::Error>> {
+        Self::read_async_with(
+            s,
+            WireFormat::default(),
+            Version::default(),
+            Crc32::default(),
+        )
+        .await
+    }
+
+    /// Read in a raw packet of any capacity, using the given wire format, header version and
+    /// checksum.
+    ///
+    /// Bytes are read one at a time, so nothing following the packet is consumed. The end of
+    /// the stream part way through a packet is reported as `Error::Framing`.
+    pub async fn read_async_with<S: Read, K: Checksum>(
+        mut s: S,
+        format: WireFormat,
+        version: Version,
+        checksum: K,
+    ) -> Result<Self, Error<S::Error>> {
+        let mut decoder = PacketDecoder::<N>::with_format_sized(format)
+            .with_version(version)
+            .with_checksum(checksum);
+        let mut byte = [0];
+        loop {
+            if s.read(&mut byte).await.map_err(Error::Serial)? == 0 {
+                return Err(Error::Framing);
+            }
+            match decoder.feed(byte[0]) {
+                Ok(packet) => return Ok(packet),
+                Err(nb::Error::WouldBlock) => {}
+                Err(nb::Error::Other(e)) => return Err(e.widen()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::convert::Infallible;
+    use core::future::Future;
+    use core::pin::pin;
+    use core::task::{Context, Poll, Waker};
+    use std::collections::VecDeque;
+
+    use super::*;
+    use crate::crc::Crc16Ccitt;
+    use crate::sim::MockSerial;
+    use crate::{Addr, PacketType};
+
+    /// Runs a future which never has to wait, as none of the streams here do
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = pin!(future);
+        let mut cx = Context::from_waker(Waker::noop());
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+        }
+    }
+
+    /// An in-memory stream, which reads back what was written and then reaches its end
+    #[derive(Default)]
+    struct Pipe(VecDeque<u8>);
+
+    impl embedded_io_async::ErrorType for Pipe {
+        type Error = Infallible;
+    }
+
+    impl Read for Pipe {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Infallible> {
+            let n = buf.len().min(self.0.len());
+            for (b, d) in buf.iter_mut().zip(self.0.drain(..n)) {
+                *b = d;
+            }
+            Ok(n)
+        }
+    }
+
+    impl Write for Pipe {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, Infallible> {
+            self.0.extend(buf);
+            Ok(buf.len())
+        }
+
+        async fn flush(&mut self) -> Result<(), Infallible> {
+            Ok(())
+        }
+    }
+
+    /// A packet long enough to be written in several chunks, with bytes which need escaping
+    fn packet() -> Packet<Raw> {
+        let data: Raw = (0..24).map(|i| if i % 5 == 0 { 0xC0 } else { i }).collect();
+        Packet::new(PacketType::Command, Addr(5), data).with_source(Addr(2))
+    }
+
+    #[test]
+    fn round_trip() {
+        let packet = packet();
+        for format in [WireFormat::Framed, WireFormat::Legacy] {
+            let mut pipe = Pipe::default();
+            let checksum = Crc16Ccitt::default();
+            block_on(packet.write_async_with(&mut pipe, format, Version::V2, checksum)).unwrap();
+
+            // The same bytes as the blocking functions write
+            let serial = MockSerial::new();
+            packet
+                .write_raw_with(&serial, format, Version::V2, checksum)
+                .unwrap();
+            assert!(pipe.0.iter().eq(serial.take().iter()));
+
+            let read: Packet<Raw> = block_on(Packet::read_async_with(
+                &mut pipe,
+                format,
+                Version::V2,
+                checksum,
+            ))
+            .unwrap();
+            assert_eq!(read.target(), packet.target());
+            assert_eq!(read.source(), packet.source());
+            assert_eq!(read.data(), packet.data());
+            assert!(pipe.0.is_empty());
+        }
+
+        // With the defaults
+        let mut pipe = Pipe::default();
+        block_on(packet.write_async(&mut pipe)).unwrap();
+        let read = block_on(Packet::read_async(&mut pipe)).unwrap();
+        assert_eq!(read.data(), packet.data());
+    }
+
+    #[test]
+    fn early_eof() {
+        let mut pipe = Pipe::default();
+        block_on(packet().write_async(&mut pipe)).unwrap();
+        pipe.0.truncate(pipe.0.len() - 3);
+        assert_eq!(
+            block_on(Packet::read_async(&mut pipe)).unwrap_err(),
+            Error::Framing
+        );
+        assert_eq!(
+            block_on(Packet::read_async(&mut Pipe::default())).unwrap_err(),
+            Error::Framing
+        );
+    }
+}
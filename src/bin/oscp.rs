@@ -0,0 +1,459 @@
+//! Command line tool for talking to OSCP modules from a host, over a serial port or TCP.
+//!
+//! ```text
+//! oscp --port /dev/ttyUSB0 send --to 3 set-param 12 0.5
+//! oscp --tcp localhost:4000 tail --capture bus.bin
+//! oscp --port /dev/ttyUSB0 replay bus.bin
+//! ```
+
+use std::error::Error as StdError;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+
+use oscp::command::{Command, ParamId, Response};
+use oscp::crc::{Checksum, Crc16Ccitt, Crc32, Crc8};
+use oscp::enumeration::Uid;
+use oscp::io::StdIo;
+use oscp::midi::MidiEvent;
+use oscp::{Addr, Error, Packet, PacketType, Raw, Version, WireFormat, BROADCAST, CONTROLLER};
+
+type Result<T> = std::result::Result<T, Box<dyn StdError>>;
+
+/// How long a read waits for a byte, so that deadlines are noticed while the link is quiet
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Send and sniff Ono-Sendai Control Protocol packets
+#[derive(Parser)]
+#[command(name = "oscp", version)]
+struct Cli {
+    #[command(flatten)]
+    link: LinkArgs,
+    #[command(subcommand)]
+    action: Action,
+}
+
+#[derive(Args)]
+struct LinkArgs {
+    /// Serial port to open, e.g. /dev/ttyUSB0
+    #[arg(long, global = true, env = "OSCP_PORT")]
+    port: Option<String>,
+    /// TCP endpoint to connect to instead of a serial port, e.g. localhost:4000
+    #[arg(long, global = true)]
+    tcp: Option<String>,
+    /// Baud rate of the serial port
+    #[arg(long, global = true, default_value_t = 115_200)]
+    baud: u32,
+    /// Use the legacy wire format, without framing
+    #[arg(long, global = true)]
+    legacy: bool,
+    /// Header version used on the link
+    #[arg(
+        long,
+        global = true,
+        default_value_t = Version::LATEST as u8,
+        value_parser = clap::value_parser!(u8).range(1..=Version::LATEST as i64),
+    )]
+    header_version: u8,
+    /// Checksum used on the link
+    #[arg(long, global = true, value_enum, default_value_t = ChecksumKind::Crc32)]
+    checksum: ChecksumKind,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ChecksumKind {
+    Crc8,
+    Crc16,
+    Crc32,
+}
+
+#[derive(Subcommand)]
+enum Action {
+    /// Send a command, and print the packets received until the response arrives
+    Send {
+        /// Address of the module, or "all" to broadcast
+        #[arg(long, value_parser = parse_addr)]
+        to: Addr,
+        /// Source address of the command
+        #[arg(long, value_parser = parse_addr, default_value = "controller")]
+        from: Addr,
+        /// How long to wait for a response, in milliseconds. Broadcasts wait for the whole
+        /// time, printing every packet received.
+        #[arg(long, default_value_t = 500)]
+        wait_ms: u64,
+        #[command(subcommand)]
+        command: CommandArg,
+    },
+    /// Print packets as they are received, including those which fail to decode
+    Tail {
+        /// Also save the bytes received to a file, for `replay`
+        #[arg(long)]
+        capture: Option<PathBuf>,
+    },
+    /// Send the packets saved in a capture file
+    Replay {
+        file: PathBuf,
+        /// Time to wait between packets, in milliseconds
+        #[arg(long, default_value_t = 0)]
+        interval_ms: u64,
+    },
+}
+
+/// The commands which can be sent, as in `command::Command`
+#[derive(Subcommand)]
+enum CommandArg {
+    Ping,
+    GetParam {
+        id: ParamId,
+    },
+    SetParam {
+        id: ParamId,
+        #[arg(allow_negative_numbers = true)]
+        value: f32,
+    },
+    Reset,
+    Identify,
+    FirmwareVersion,
+    StorePatch {
+        slot: u8,
+    },
+    RecallPatch {
+        slot: u8,
+    },
+    Discover {
+        window_ms: u16,
+    },
+    AssignAddress {
+        #[arg(value_parser = parse_uid)]
+        uid: Uid,
+        #[arg(value_parser = parse_addr)]
+        addr: Addr,
+    },
+    Hello {
+        #[arg(default_value_t = Version::LATEST as u8)]
+        version: u8,
+    },
+}
+
+impl From<&CommandArg> for Command {
+    fn from(arg: &CommandArg) -> Self {
+        match *arg {
+            CommandArg::Ping => Command::Ping,
+            CommandArg::GetParam { id } => Command::GetParam { id },
+            CommandArg::SetParam { id, value } => Command::SetParam { id, value },
+            CommandArg::Reset => Command::Reset,
+            CommandArg::Identify => Command::Identify,
+            CommandArg::FirmwareVersion => Command::GetFirmwareVersion,
+            CommandArg::StorePatch { slot } => Command::StorePatch { slot },
+            CommandArg::RecallPatch { slot } => Command::RecallPatch { slot },
+            CommandArg::Discover { window_ms } => Command::Discover { window_ms },
+            CommandArg::AssignAddress { uid, addr } => Command::AssignAddress { uid, addr },
+            CommandArg::Hello { version } => Command::Hello { version },
+        }
+    }
+}
+
+fn parse_addr(s: &str) -> std::result::Result<Addr, String> {
+    match s {
+        "all" | "broadcast" => Ok(BROADCAST),
+        "controller" => Ok(CONTROLLER),
+        _ => match s.parse::<u8>() {
+            Ok(a) if a == CONTROLLER.get() => Ok(CONTROLLER),
+            Ok(a) if a == BROADCAST.get() => Ok(BROADCAST),
+            Ok(a) => Ok(Addr::new(a).expect("reserved addresses handled above")),
+            Err(e) => Err(e.to_string()),
+        },
+    }
+}
+
+fn parse_uid(s: &str) -> std::result::Result<Uid, String> {
+    match s.strip_prefix("0x") {
+        Some(hex) => Uid::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|e| e.to_string())
+}
+
+trait Link: Read + Write {}
+
+impl<T: Read + Write> Link for T {}
+
+/// Settings which both ends of the link must agree on
+#[derive(Clone, Copy)]
+struct Wire {
+    format: WireFormat,
+    version: Version,
+}
+
+impl Wire {
+    fn read<K: Checksum + Default>(
+        self,
+        r: impl Read,
+    ) -> std::result::Result<Packet<Raw>, Error<io::Error>> {
+        Packet::read_raw_with(StdIo(r), self.format, self.version, K::default())
+    }
+
+    /// Write a packet, buffered so that it goes to the link in one write rather than one per
+    /// byte
+    fn write<K: Checksum + Default>(self, w: impl Write, packet: &Packet<Raw>) -> Result<()> {
+        let mut w = BufWriter::new(w);
+        packet.write_raw_with(StdIo(&mut w), self.format, self.version, K::default())?;
+        w.flush()?;
+        Ok(())
+    }
+}
+
+fn open(args: &LinkArgs) -> Result<Box<dyn Link>> {
+    match (&args.tcp, &args.port) {
+        (Some(addr), _) => {
+            let stream = TcpStream::connect(addr)?;
+            stream.set_read_timeout(Some(READ_TIMEOUT))?;
+            Ok(Box::new(stream))
+        }
+        (None, Some(port)) => {
+            let port = serialport::new(port, args.baud)
+                .timeout(READ_TIMEOUT)
+                .open()?;
+            Ok(Box::new(port))
+        }
+        (None, None) => Err("no link given: use --port or --tcp, or set OSCP_PORT".into()),
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let result = match cli.link.checksum {
+        ChecksumKind::Crc8 => run::<Crc8>(&cli),
+        ChecksumKind::Crc16 => run::<Crc16Ccitt>(&cli),
+        ChecksumKind::Crc32 => run::<Crc32>(&cli),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("oscp: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run<K: Checksum + Default>(cli: &Cli) -> Result<()> {
+    let wire = Wire {
+        format: match cli.link.legacy {
+            true => WireFormat::Legacy,
+            false => WireFormat::Framed,
+        },
+        version: Version::from_u8(cli.link.header_version).ok_or("unknown header version")?,
+    };
+    let link = open(&cli.link)?;
+    let printer = Printer::new();
+
+    match &cli.action {
+        Action::Send {
+            to,
+            from,
+            wait_ms,
+            command,
+        } => {
+            let packet = Packet::command(*to, Command::from(command))
+                .with_source(*from)
+                .encoded();
+            send::<K>(link, wire, &printer, &packet, *from, *wait_ms)
+        }
+        Action::Tail { capture } => {
+            let capture = capture.as_ref().map(File::create).transpose()?;
+            tail::<K>(link, wire, &printer, capture)
+        }
+        Action::Replay { file, interval_ms } => {
+            let file = BufReader::new(File::open(file)?);
+            replay::<K>(link, wire, &printer, file, *interval_ms)
+        }
+    }
+}
+
+fn send<K: Checksum + Default>(
+    mut link: Box<dyn Link>,
+    wire: Wire,
+    printer: &Printer,
+    packet: &Packet<Raw>,
+    from: Addr,
+    wait_ms: u64,
+) -> Result<()> {
+    // The handshake is sent with V1 headers, which every module understands
+    let hello = matches!(packet.decoded(), Ok(p) if matches!(p.data(), Command::Hello { .. }));
+    let out = match hello {
+        true => Wire {
+            version: Version::V1,
+            ..wire
+        },
+        false => wire,
+    };
+    out.write::<K>(&mut link, packet)?;
+    link.flush()?;
+    printer.packet(">", packet);
+
+    let deadline = Instant::now() + Duration::from_millis(wait_ms);
+    while Instant::now() < deadline {
+        match wire.read::<K>(&mut link) {
+            Ok(p) => {
+                printer.packet("<", &p);
+                let answered = p.target() == from && p.decoded::<Response>().is_ok();
+                if answered && !packet.target().is_broadcast() {
+                    return Ok(());
+                }
+            }
+            Err(Error::Timeout) => {}
+            Err(Error::Serial(e)) => return Err(e.into()),
+            Err(e) => printer.error(&e),
+        }
+    }
+    match packet.target().is_broadcast() || wait_ms == 0 {
+        true => Ok(()),
+        false => Err("no response".into()),
+    }
+}
+
+fn tail<K: Checksum + Default>(
+    link: Box<dyn Link>,
+    wire: Wire,
+    printer: &Printer,
+    capture: Option<File>,
+) -> Result<()> {
+    let mut input = BufReader::new(Tee {
+        input: link,
+        capture,
+    });
+    loop {
+        match wire.read::<K>(&mut input) {
+            Ok(p) => printer.packet("<", &p),
+            Err(Error::Timeout) => {}
+            Err(Error::Serial(e)) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(Error::Serial(e)) => return Err(e.into()),
+            Err(e) => printer.error(&e),
+        }
+    }
+}
+
+fn replay<K: Checksum + Default>(
+    mut link: Box<dyn Link>,
+    wire: Wire,
+    printer: &Printer,
+    mut file: impl Read,
+    interval_ms: u64,
+) -> Result<()> {
+    loop {
+        match wire.read::<K>(&mut file) {
+            Ok(p) => {
+                wire.write::<K>(&mut link, &p)?;
+                link.flush()?;
+                printer.packet(">", &p);
+                thread::sleep(Duration::from_millis(interval_ms));
+            }
+            Err(Error::Serial(e)) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(Error::Serial(e)) => return Err(e.into()),
+            Err(e) => printer.error(&e),
+        }
+    }
+}
+
+/// Copies everything read from the link to a capture file
+struct Tee {
+    input: Box<dyn Link>,
+    capture: Option<File>,
+}
+
+impl Read for Tee {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.input.read(buf)?;
+        if let Some(capture) = &mut self.capture {
+            capture.write_all(&buf[..n])?;
+        }
+        Ok(n)
+    }
+}
+
+/// Prints packets and errors, one per line, with the time since the tool started
+struct Printer {
+    start: Instant,
+}
+
+impl Printer {
+    fn new() -> Self {
+        Printer {
+            start: Instant::now(),
+        }
+    }
+
+    fn time(&self) -> f64 {
+        self.start.elapsed().as_secs_f64()
+    }
+
+    /// `direction` is ">" for packets sent, and "<" for those received
+    fn packet(&self, direction: &str, p: &Packet<Raw>) {
+        let mut line = format!(
+            "{:10.3} {} {} -> {} seq {:3} {:?}",
+            self.time(),
+            direction,
+            addr(p.source()),
+            addr(p.target()),
+            p.seq(),
+            p.packet_type(),
+        );
+        if !p.flags().is_empty() {
+            line += &format!(" [{:?}]", p.flags());
+        }
+        line += &format!(" {}", payload(p));
+        println!("{}", line.trim_end());
+    }
+
+    fn error(&self, e: &Error<io::Error>) {
+        println!("{:10.3} ! {}", self.time(), e);
+    }
+}
+
+fn addr(a: Addr) -> String {
+    match a {
+        CONTROLLER => "controller".into(),
+        // Also the source of packets read with V1 headers, which don't carry one
+        BROADCAST => "all".into(),
+        a => a.get().to_string(),
+    }
+}
+
+fn payload(p: &Packet<Raw>) -> String {
+    let data = p.data();
+    let decoded = match p.packet_type() {
+        // Responses have the top bit of the opcode set
+        PacketType::Command if data.first().is_some_and(|op| op & 0x80 != 0) => p
+            .decoded::<Response>()
+            .map(|r| format!("{:?}", r.data()))
+            .map_err(|e| e.to_string()),
+        PacketType::Command => p
+            .decoded::<Command>()
+            .map(|c| format!("{:?}", c.data()))
+            .map_err(|e| e.to_string()),
+        PacketType::MidiEvent => p
+            .decoded::<MidiEvent>()
+            .map(|m| format!("{:?}", m.data()))
+            .map_err(|e| e.to_string()),
+        PacketType::Ack | PacketType::Nak | PacketType::Raw => Err(String::new()),
+    };
+    match decoded {
+        Ok(d) => d,
+        Err(e) if data.is_empty() => e,
+        Err(e) if e.is_empty() => hex(data),
+        Err(e) => format!("{} ({})", hex(data), e),
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
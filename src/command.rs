@@ -1 +1,546 @@
+//! Payloads for `PacketType::Command` packets.
+//!
+//! The controller sends a `Command` to a module, which replies with a `Response`. Both are
+//! carried in `Command` packets, and are told apart by their opcode: responses have the top
+//! bit set. Multi-byte fields are little endian.
 
+use core::fmt;
+
+use bitflags::bitflags;
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::enumeration::Uid;
+use crate::packet::Capacity;
+use crate::{Addr, Decode, Encode, PacketType, Raw};
+
+/// Identifies a parameter of a module, e.g. a filter cutoff
+pub type ParamId = u16;
+
+const PING: u8 = 0x01;
+const GET_PARAM: u8 = 0x02;
+const SET_PARAM: u8 = 0x03;
+const RESET: u8 = 0x04;
+const IDENTIFY: u8 = 0x05;
+const GET_FIRMWARE_VERSION: u8 = 0x06;
+const STORE_PATCH: u8 = 0x07;
+const RECALL_PATCH: u8 = 0x08;
+const DISCOVER: u8 = 0x09;
+const ASSIGN_ADDRESS: u8 = 0x0A;
+const HELLO: u8 = 0x0B;
+
+const OK: u8 = 0x80;
+const PONG: u8 = 0x81;
+const PARAM: u8 = 0x82;
+const IDENTITY: u8 = 0x85;
+const FIRMWARE_VERSION: u8 = 0x86;
+const ANNOUNCE: u8 = 0x89;
+const HELLO_REPLY: u8 = 0x8B;
+const FAILED: u8 = 0xFF;
+
+/// Returns true if `opcode` is that of `Command::Hello` or `Response::Hello`
+pub(crate) fn is_hello(opcode: u8) -> bool {
+    matches!(opcode, HELLO | HELLO_REPLY)
+}
+
+/// A request from the controller to a module
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Command {
+    /// Check that the module is alive. Answered with `Response::Pong`.
+    Ping,
+    /// Read a parameter. Answered with `Response::Param`.
+    GetParam { id: ParamId },
+    /// Change a parameter. Answered with `Response::Ok`.
+    SetParam { id: ParamId, value: f32 },
+    /// Restart the module. Answered with `Response::Ok` before restarting.
+    Reset,
+    /// Make the module identify itself to the user, e.g. by flashing an LED. Answered with
+    /// `Response::Identity`.
+    Identify,
+    /// Answered with `Response::FirmwareVersion`.
+    GetFirmwareVersion,
+    /// Save the current parameters to a patch slot. Answered with `Response::Ok`.
+    StorePatch { slot: u8 },
+    /// Load the parameters from a patch slot. Answered with `Response::Ok`.
+    RecallPatch { slot: u8 },
+    /// Broadcast to find modules without an address. Each one answers with
+    /// `Response::Announce` at a random time within the window.
+    Discover { window_ms: u16 },
+    /// Broadcast to give the module with the given unique ID an address. Not answered.
+    AssignAddress { uid: Uid, addr: Addr },
+    /// Agree on the header `Version` to use, and find out what the module can do. Carries the
+    /// newest version the controller supports, and is answered with `Response::Hello`.
+    ///
+    /// Both are sent with `Version::V1` headers, which every module understands. A receiver
+    /// expecting a later version still accepts them, though it rejects any other packet with a
+    /// V1 header. A module which answers with `Response::Failed`, or not at all, only supports
+    /// `Version::V1`.
+    Hello { version: u8 },
+}
+
+/// A module's reply to a `Command`
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Response {
+    /// The command succeeded, and has nothing else to report
+    Ok,
+    Pong,
+    Param {
+        id: ParamId,
+        value: f32,
+    },
+    Identity {
+        /// Identifies the kind of module, e.g. a particular oscillator design
+        model: u16,
+        /// Unique to each module
+        serial: u32,
+    },
+    FirmwareVersion {
+        major: u8,
+        minor: u8,
+        patch: u8,
+    },
+    /// A module without an address answering `Command::Discover`
+    Announce {
+        uid: Uid,
+    },
+    /// The newest header version the module supports, and its capabilities. Both ends then
+    /// switch to the result of `Version::negotiate`.
+    Hello {
+        version: u8,
+        capabilities: Capabilities,
+    },
+    /// The command failed
+    Failed(ErrorCode),
+}
+
+bitflags! {
+    /// Packet types a module can handle, advertised in `Response::Hello`
+    pub struct PacketTypes: u8 {
+        const COMMAND = 0b0000_0001;
+        const MIDI_EVENT = 0b0000_0010;
+        const ACK = 0b0000_0100;
+        const NAK = 0b0000_1000;
+    }
+}
+
+impl PacketTypes {
+    /// Returns true if the given packet type is included. `PacketType::Raw` never is.
+    pub fn supports(self, typ: PacketType) -> bool {
+        let flag = match typ {
+            PacketType::Command => PacketTypes::COMMAND,
+            PacketType::MidiEvent => PacketTypes::MIDI_EVENT,
+            PacketType::Ack => PacketTypes::ACK,
+            PacketType::Nak => PacketTypes::NAK,
+            PacketType::Raw => return false,
+        };
+        self.contains(flag)
+    }
+}
+
+bitflags! {
+    /// Optional protocol features a module can support, advertised in `Response::Hello`
+    pub struct Features: u8 {
+        /// Sequenced packets are acknowledged, as by `reliable::Channel`
+        const RELIABLE = 0b0000_0001;
+        /// Payloads may be compressed
+        const COMPRESSION = 0b0000_0010;
+    }
+}
+
+/// What a module can do, advertised in `Response::Hello`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Capabilities {
+    pub packet_types: PacketTypes,
+    /// Longest payload the module can receive, i.e. the packet capacity `N` it was built with
+    pub max_payload: u8,
+    pub features: Features,
+}
+
+/// Reasons a module can give for a command failing
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ErrorCode {
+    /// The module does not support the command
+    UnsupportedCommand,
+    /// The module has no parameter with the given ID
+    UnknownParam,
+    /// The value is out of range for the parameter
+    InvalidValue,
+    /// The module has no patch slot with the given number
+    InvalidSlot,
+    /// A code not known to this version of the protocol
+    Other(u8),
+}
+
+impl ErrorCode {
+    fn to_u8(self) -> u8 {
+        match self {
+            ErrorCode::UnsupportedCommand => 0x01,
+            ErrorCode::UnknownParam => 0x02,
+            ErrorCode::InvalidValue => 0x03,
+            ErrorCode::InvalidSlot => 0x04,
+            ErrorCode::Other(code) => code,
+        }
+    }
+
+    fn from_u8(code: u8) -> Self {
+        match code {
+            0x01 => ErrorCode::UnsupportedCommand,
+            0x02 => ErrorCode::UnknownParam,
+            0x03 => ErrorCode::InvalidValue,
+            0x04 => ErrorCode::InvalidSlot,
+            code => ErrorCode::Other(code),
+        }
+    }
+}
+
+/// Reasons a `Command` or `Response` payload can fail to decode
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DecodeError {
+    /// The opcode is not a known command or response
+    UnknownOpcode(u8),
+    /// The payload length is wrong for the opcode
+    Length(usize),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnknownOpcode(op) => write!(f, "unknown opcode {:#04x}", op),
+            DecodeError::Length(len) => write!(f, "wrong payload length {}", len),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for DecodeError {}
+
+impl<const N: usize> Encode<N> for Command {
+    fn data(&self) -> Raw<N> {
+        let mut w = Writer::new();
+        match *self {
+            Command::Ping => w.u8(PING),
+            Command::GetParam { id } => w.u8(GET_PARAM).u16(id),
+            Command::SetParam { id, value } => w.u8(SET_PARAM).u16(id).f32(value),
+            Command::Reset => w.u8(RESET),
+            Command::Identify => w.u8(IDENTIFY),
+            Command::GetFirmwareVersion => w.u8(GET_FIRMWARE_VERSION),
+            Command::StorePatch { slot } => w.u8(STORE_PATCH).u8(slot),
+            Command::RecallPatch { slot } => w.u8(RECALL_PATCH).u8(slot),
+            Command::Discover { window_ms } => w.u8(DISCOVER).u16(window_ms),
+            Command::AssignAddress { uid, addr } => w.u8(ASSIGN_ADDRESS).u32(uid).u8(addr.get()),
+            Command::Hello { version } => w.u8(HELLO).u8(version),
+        };
+        w.0
+    }
+}
+
+impl<const N: usize> Decode<N> for Command {
+    type Error = DecodeError;
+
+    fn decode(raw: Raw<N>) -> Result<Self, Self::Error> {
+        let mut r = Reader::new(&raw);
+        let command = match r.u8()? {
+            PING => Command::Ping,
+            GET_PARAM => Command::GetParam { id: r.u16()? },
+            SET_PARAM => Command::SetParam {
+                id: r.u16()?,
+                value: r.f32()?,
+            },
+            RESET => Command::Reset,
+            IDENTIFY => Command::Identify,
+            GET_FIRMWARE_VERSION => Command::GetFirmwareVersion,
+            STORE_PATCH => Command::StorePatch { slot: r.u8()? },
+            RECALL_PATCH => Command::RecallPatch { slot: r.u8()? },
+            DISCOVER => Command::Discover {
+                window_ms: r.u16()?,
+            },
+            ASSIGN_ADDRESS => Command::AssignAddress {
+                uid: r.u32()?,
+                addr: Addr(r.u8()?),
+            },
+            HELLO => Command::Hello { version: r.u8()? },
+            op => return Err(DecodeError::UnknownOpcode(op)),
+        };
+        r.finish()?;
+        Ok(command)
+    }
+}
+
+impl<const N: usize> Encode<N> for Response {
+    fn data(&self) -> Raw<N> {
+        let mut w = Writer::new();
+        match *self {
+            Response::Ok => w.u8(OK),
+            Response::Pong => w.u8(PONG),
+            Response::Param { id, value } => w.u8(PARAM).u16(id).f32(value),
+            Response::Identity { model, serial } => w.u8(IDENTITY).u16(model).u32(serial),
+            Response::FirmwareVersion {
+                major,
+                minor,
+                patch,
+            } => w.u8(FIRMWARE_VERSION).u8(major).u8(minor).u8(patch),
+            Response::Announce { uid } => w.u8(ANNOUNCE).u32(uid),
+            Response::Hello {
+                version,
+                capabilities,
+            } => w
+                .u8(HELLO_REPLY)
+                .u8(version)
+                .u8(capabilities.packet_types.bits())
+                .u8(capabilities.max_payload)
+                .u8(capabilities.features.bits()),
+            Response::Failed(code) => w.u8(FAILED).u8(code.to_u8()),
+        };
+        w.0
+    }
+}
+
+impl<const N: usize> Decode<N> for Response {
+    type Error = DecodeError;
+
+    fn decode(raw: Raw<N>) -> Result<Self, Self::Error> {
+        let mut r = Reader::new(&raw);
+        let response = match r.u8()? {
+            OK => Response::Ok,
+            PONG => Response::Pong,
+            PARAM => Response::Param {
+                id: r.u16()?,
+                value: r.f32()?,
+            },
+            IDENTITY => Response::Identity {
+                model: r.u16()?,
+                serial: r.u32()?,
+            },
+            FIRMWARE_VERSION => Response::FirmwareVersion {
+                major: r.u8()?,
+                minor: r.u8()?,
+                patch: r.u8()?,
+            },
+            ANNOUNCE => Response::Announce { uid: r.u32()? },
+            // Types and features added by later versions are ignored
+            HELLO_REPLY => Response::Hello {
+                version: r.u8()?,
+                capabilities: Capabilities {
+                    packet_types: PacketTypes::from_bits_truncate(r.u8()?),
+                    max_payload: r.u8()?,
+                    features: Features::from_bits_truncate(r.u8()?),
+                },
+            },
+            FAILED => Response::Failed(ErrorCode::from_u8(r.u8()?)),
+            op => return Err(DecodeError::UnknownOpcode(op)),
+        };
+        r.finish()?;
+        Ok(response)
+    }
+}
+
+/// Builds up a payload. Payloads here are all much shorter than `PACKET_LEN`, and `N` fails to
+/// compile if it is any less, so running out of space is not checked for.
+struct Writer<const N: usize>(Raw<N>);
+
+impl<const N: usize> Writer<N> {
+    fn new() -> Self {
+        let () = Capacity::<N>::FITS_PAYLOADS;
+        Writer(Raw::new())
+    }
+
+    fn bytes(&mut self, b: &[u8]) -> &mut Self {
+        let _ = self.0.extend_from_slice(b);
+        self
+    }
+
+    fn u8(&mut self, v: u8) -> &mut Self {
+        self.bytes(&[v])
+    }
+
+    fn u16(&mut self, v: u16) -> &mut Self {
+        let mut buf = [0; 2];
+        LittleEndian::write_u16(&mut buf, v);
+        self.bytes(&buf)
+    }
+
+    fn u32(&mut self, v: u32) -> &mut Self {
+        let mut buf = [0; 4];
+        LittleEndian::write_u32(&mut buf, v);
+        self.bytes(&buf)
+    }
+
+    fn f32(&mut self, v: f32) -> &mut Self {
+        let mut buf = [0; 4];
+        LittleEndian::write_f32(&mut buf, v);
+        self.bytes(&buf)
+    }
+}
+
+/// Takes fields from the front of a payload
+struct Reader<'a> {
+    raw: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(raw: &'a [u8]) -> Self {
+        Reader { raw, pos: 0 }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        let b = self
+            .raw
+            .get(self.pos..self.pos + len)
+            .ok_or(DecodeError::Length(self.raw.len()))?;
+        self.pos += len;
+        Ok(b)
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, DecodeError> {
+        Ok(LittleEndian::read_u16(self.bytes(2)?))
+    }
+
+    fn u32(&mut self) -> Result<u32, DecodeError> {
+        Ok(LittleEndian::read_u32(self.bytes(4)?))
+    }
+
+    fn f32(&mut self) -> Result<f32, DecodeError> {
+        Ok(LittleEndian::read_f32(self.bytes(4)?))
+    }
+
+    /// Check that the whole payload has been read
+    fn finish(&self) -> Result<(), DecodeError> {
+        if self.pos == self.raw.len() {
+            Ok(())
+        } else {
+            Err(DecodeError::Length(self.raw.len()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<T: Encode + Decode<Error = DecodeError> + PartialEq + fmt::Debug>(
+        value: T,
+        len: usize,
+    ) {
+        let raw = value.data();
+        assert_eq!(raw.len(), len, "{:?}", value);
+        assert_eq!(T::decode(raw), Ok(value));
+    }
+
+    fn decode<T: Decode<Error = DecodeError>>(bytes: &[u8]) -> Result<T, DecodeError> {
+        T::decode(Raw::from_slice(bytes).unwrap())
+    }
+
+    #[test]
+    fn commands() {
+        round_trip(Command::Ping, 1);
+        round_trip(Command::GetParam { id: 0x1234 }, 3);
+        round_trip(Command::SetParam { id: 7, value: -0.5 }, 7);
+        round_trip(Command::Reset, 1);
+        round_trip(Command::Identify, 1);
+        round_trip(Command::GetFirmwareVersion, 1);
+        round_trip(Command::StorePatch { slot: 3 }, 2);
+        round_trip(Command::RecallPatch { slot: 4 }, 2);
+        round_trip(Command::Discover { window_ms: 100 }, 3);
+        let uid = 0xDEAD_BEEF;
+        round_trip(Command::AssignAddress { uid, addr: Addr(9) }, 6);
+        round_trip(Command::Hello { version: 3 }, 2);
+
+        // Multi-byte fields are little endian
+        let raw: Raw = Command::GetParam { id: 0x1234 }.data();
+        assert_eq!(&raw[..], &[GET_PARAM, 0x34, 0x12]);
+    }
+
+    #[test]
+    fn responses() {
+        round_trip(Response::Ok, 1);
+        round_trip(Response::Pong, 1);
+        round_trip(
+            Response::Param {
+                id: 7,
+                value: 440.0,
+            },
+            7,
+        );
+        round_trip(
+            Response::Identity {
+                model: 2,
+                serial: 0x0102_0304,
+            },
+            7,
+        );
+        let version = Response::FirmwareVersion {
+            major: 1,
+            minor: 2,
+            patch: 3,
+        };
+        round_trip(version, 4);
+        round_trip(Response::Announce { uid: 42 }, 5);
+        let capabilities = Capabilities {
+            packet_types: PacketTypes::COMMAND | PacketTypes::MIDI_EVENT,
+            max_payload: 64,
+            features: Features::RELIABLE,
+        };
+        round_trip(
+            Response::Hello {
+                version: 3,
+                capabilities,
+            },
+            5,
+        );
+        round_trip(Response::Failed(ErrorCode::UnsupportedCommand), 2);
+        round_trip(Response::Failed(ErrorCode::UnknownParam), 2);
+        round_trip(Response::Failed(ErrorCode::InvalidValue), 2);
+        round_trip(Response::Failed(ErrorCode::InvalidSlot), 2);
+        round_trip(Response::Failed(ErrorCode::Other(0x42)), 2);
+    }
+
+    #[test]
+    fn lengths() {
+        // Short, and with extra bytes
+        assert_eq!(decode::<Command>(&[]), Err(DecodeError::Length(0)));
+        assert_eq!(
+            decode::<Command>(&[GET_PARAM, 1]),
+            Err(DecodeError::Length(2))
+        );
+        assert_eq!(decode::<Command>(&[PING, 0]), Err(DecodeError::Length(2)));
+        assert_eq!(
+            decode::<Command>(&[HELLO, 3, 0]),
+            Err(DecodeError::Length(3))
+        );
+        assert_eq!(decode::<Response>(&[]), Err(DecodeError::Length(0)));
+        assert_eq!(
+            decode::<Response>(&[ANNOUNCE, 1, 2, 3]),
+            Err(DecodeError::Length(4))
+        );
+        assert_eq!(decode::<Response>(&[OK, 0]), Err(DecodeError::Length(2)));
+    }
+
+    #[test]
+    fn unknown_opcodes() {
+        assert_eq!(
+            decode::<Command>(&[0x00]),
+            Err(DecodeError::UnknownOpcode(0x00))
+        );
+        assert_eq!(
+            decode::<Command>(&[0x7F, 1]),
+            Err(DecodeError::UnknownOpcode(0x7F))
+        );
+        // Responses aren't commands, and the other way round
+        assert_eq!(
+            decode::<Command>(&[PONG]),
+            Err(DecodeError::UnknownOpcode(PONG))
+        );
+        assert_eq!(
+            decode::<Response>(&[PING]),
+            Err(DecodeError::UnknownOpcode(PING))
+        );
+        assert_eq!(
+            decode::<Response>(&[0x90]),
+            Err(DecodeError::UnknownOpcode(0x90))
+        );
+    }
+}
@@ -0,0 +1,431 @@
+//! Checksums, and the serial device wrappers which calculate them as a packet is written or
+//! read.
+//!
+//! Each algorithm is available with a choice of implementations, which all produce the same
+//! checksums: the ones here use full 256 entry lookup tables, which are fastest but take up to
+//! 1 KiB of flash, while those in `bitwise` and `nibble` need none or 16 entries. A CRC unit on
+//! the MCU can be used with `Hardware`.
+
+use ::crc::{crc16, crc32};
+
+use crate::framing::{FrameInput, FrameOutput, WireFormat};
+use crate::io::{SerialRead, SerialWrite};
+use crate::Error;
+
+pub mod bitwise;
+pub mod nibble;
+
+/// A checksum value, wide enough for any `Checksum`
+pub type CRC = u32;
+
+/// Largest number of checksum bytes following a packet
+pub(crate) const MAX_CHECKSUM_LEN: usize = 4;
+
+/// A checksum algorithm, used to detect corrupted packets. Both ends of a link must use the
+/// same one.
+pub trait Checksum {
+    /// Number of checksum bytes following each packet, from 1 to 4. Other lengths fail to
+    /// compile when the checksum is used.
+    const LEN: usize;
+
+    /// Start a new checksum
+    fn reset(&mut self);
+
+    /// Add bytes to the checksum
+    fn update(&mut self, data: &[u8]);
+
+    /// The checksum of the bytes added since the last reset. Only the low `LEN` bytes are sent.
+    fn value(&mut self) -> CRC;
+}
+
+impl<K: Checksum> Checksum for &mut K {
+    const LEN: usize = K::LEN;
+
+    fn reset(&mut self) {
+        (**self).reset()
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        (**self).update(data)
+    }
+
+    fn value(&mut self) -> CRC {
+        (**self).value()
+    }
+}
+
+/// CRC-8 with polynomial 0x07 (CRC-8/SMBUS). Only one byte per packet, for slow links and the
+/// smallest MCUs, at the cost of letting more corrupted packets through.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Crc8(u8);
+
+const CRC8_POLY: u8 = 0x07;
+
+// Polynomials of the reflected algorithms, with the bits reversed
+const CRC16_POLY: u16 = 0x8408;
+const CRC32_POLY: u32 = 0xEDB8_8320;
+
+const CRC8_TABLE: [u8; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u8;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ CRC8_POLY
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+impl Checksum for Crc8 {
+    const LEN: usize = 1;
+
+    fn reset(&mut self) {
+        self.0 = 0;
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for d in data {
+            self.0 = CRC8_TABLE[usize::from(self.0 ^ d)];
+        }
+    }
+
+    fn value(&mut self) -> CRC {
+        CRC::from(self.0)
+    }
+}
+
+/// CRC-16/CCITT, as used by X.25 and HDLC (CRC-16/X-25)
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Crc16Ccitt(u16);
+
+impl Checksum for Crc16Ccitt {
+    const LEN: usize = 2;
+
+    fn reset(&mut self) {
+        self.0 = 0;
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.0 = crc16::update(self.0, &crc16::X25_TABLE, data);
+    }
+
+    fn value(&mut self) -> CRC {
+        CRC::from(self.0)
+    }
+}
+
+/// CRC-32 (IEEE 802.3). The default, as understood by older firmware.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Crc32(u32);
+
+impl Checksum for Crc32 {
+    const LEN: usize = 4;
+
+    fn reset(&mut self) {
+        self.0 = 0;
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.0 = crc32::update(self.0, &crc32::IEEE_TABLE, data);
+    }
+
+    fn value(&mut self) -> CRC {
+        self.0
+    }
+}
+
+/// A CRC calculation unit, e.g. the CRC peripheral of an STM32, set up by the HAL for the
+/// algorithm used on the link.
+///
+/// The result must match one of the software checksums if the other end of the link uses it.
+/// For CRC-32, an STM32 unit needs input and output bit reversal enabled, and the result
+/// inverted.
+pub trait CrcPeripheral {
+    /// Load the initial value, ready for a new checksum
+    fn reset(&mut self);
+
+    /// Add bytes to the checksum
+    fn feed(&mut self, data: &[u8]);
+
+    /// The checksum of the bytes fed in since the last reset, after any final XOR
+    fn result(&mut self) -> CRC;
+}
+
+/// Calculates a checksum of `BYTES` bytes with a `CrcPeripheral`. `BYTES` must be from 1 to 4.
+#[derive(Debug)]
+pub struct Hardware<P, const BYTES: usize>(pub P);
+
+impl<P: CrcPeripheral, const BYTES: usize> Checksum for Hardware<P, BYTES> {
+    const LEN: usize = BYTES;
+
+    fn reset(&mut self) {
+        self.0.reset()
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.0.feed(data)
+    }
+
+    fn value(&mut self) -> CRC {
+        self.0.result()
+    }
+}
+
+/// Holds the check on `Checksum::LEN`, evaluated when a checksum is used
+struct Len<K>(core::marker::PhantomData<K>);
+
+impl<K: Checksum> Len<K> {
+    const CHECKED: usize = {
+        assert!(
+            K::LEN >= 1 && K::LEN <= MAX_CHECKSUM_LEN,
+            "checksum length must be from 1 to 4 bytes"
+        );
+        K::LEN
+    };
+}
+
+/// Number of checksum bytes following each packet, failing to compile if it is out of range
+pub(crate) fn len<K: Checksum>() -> usize {
+    Len::<K>::CHECKED
+}
+
+/// The checksum bytes sent on the wire, in the first `len::<K>()` bytes
+pub(crate) fn to_bytes(value: CRC) -> [u8; MAX_CHECKSUM_LEN] {
+    value.to_le_bytes()
+}
+
+/// Parse checksum bytes received from the wire
+pub(crate) fn from_bytes<K: Checksum>(bytes: &[u8]) -> CRC {
+    let mut buf = [0; MAX_CHECKSUM_LEN];
+    let len = len::<K>();
+    buf[..len].copy_from_slice(&bytes[..len]);
+    CRC::from_le_bytes(buf)
+}
+
+/// The checksum value, without any bits which aren't sent
+pub(crate) fn truncate<K: Checksum>(value: CRC) -> CRC {
+    match len::<K>() {
+        n @ 1..=3 => value & ((1 << (8 * n)) - 1),
+        _ => value,
+    }
+}
+
+/// Writes data to a serial device, and calculates the checksum as data is written
+pub(crate) struct DigesterOutput<O, K> {
+    output: FrameOutput<O>,
+    checksum: K,
+}
+
+impl<O: SerialWrite, K: Checksum> DigesterOutput<O, K> {
+    pub(crate) fn new(output: O, format: WireFormat, mut checksum: K) -> Self {
+        checksum.reset();
+        let output = FrameOutput::new(output, format);
+        Self { output, checksum }
+    }
+
+    /// Start a new frame
+    pub(crate) fn begin(&mut self) -> Result<(), Error<O::Error>> {
+        self.output.begin()
+    }
+
+    /// Finish the current frame
+    pub(crate) fn end(&mut self) -> Result<(), Error<O::Error>> {
+        self.output.end()
+    }
+
+    /// Write a single byte
+    pub(crate) fn write(&mut self, d: u8) -> Result<(), Error<O::Error>> {
+        self.output.write(d)?;
+        self.checksum.update(&[d]);
+        Ok(())
+    }
+
+    /// Write a number of bytes from a buffer.
+    pub(crate) fn write_data(&mut self, d: &[u8]) -> Result<(), Error<O::Error>> {
+        for b in d {
+            self.write(*b)?;
+        }
+        Ok(())
+    }
+
+    /// Write out the calculated checksum, and return it.
+    pub(crate) fn write_checksum(&mut self) -> Result<CRC, Error<O::Error>> {
+        let digest = truncate::<K>(self.checksum.value());
+        for b in &to_bytes(digest)[..len::<K>()] {
+            self.output.write(*b)?;
+        }
+        Ok(digest)
+    }
+}
+
+/// Reads data from a serial device, and cumulatively calculates the checksum
+pub(crate) struct DigesterInput<I, K> {
+    input: FrameInput<I>,
+    checksum: K,
+}
+
+impl<I: SerialRead, K: Checksum> DigesterInput<I, K> {
+    pub(crate) fn new(input: I, format: WireFormat, mut checksum: K) -> Self {
+        checksum.reset();
+        let input = FrameInput::new(input, format);
+        Self { input, checksum }
+    }
+
+    /// Discard input up to the start of the next frame
+    pub(crate) fn sync(&mut self) -> Result<(), Error<I::Error>> {
+        self.input.sync()
+    }
+
+    /// Check that the frame ends here
+    pub(crate) fn end(&mut self) -> Result<(), Error<I::Error>> {
+        self.input.end()
+    }
+
+    /// Read a single byte
+    pub(crate) fn read(&mut self) -> Result<u8, Error<I::Error>> {
+        let d = self.input.read()?;
+        self.checksum.update(&[d]);
+
+        Ok(d)
+    }
+
+    /// Read enough bytes to fill a buffer
+    pub(crate) fn read_data(&mut self, buf: &mut [u8]) -> Result<(), Error<I::Error>> {
+        for b in buf.iter_mut() {
+            *b = self.read()?;
+        }
+        Ok(())
+    }
+
+    /// Read the checksum from the stream, and compare it to the calculated checksum
+    pub(crate) fn read_checksum(&mut self) -> Result<(), Error<I::Error>> {
+        let mut buf = [0; MAX_CHECKSUM_LEN];
+        for b in buf[..len::<K>()].iter_mut() {
+            *b = self.input.read()?;
+        }
+        let packet_checksum = from_bytes::<K>(&buf);
+        let calc_checksum = truncate::<K>(self.checksum.value());
+        if packet_checksum == calc_checksum {
+            Ok(())
+        } else {
+            Err(Error::CrcMismatch {
+                expected: calc_checksum,
+                received: packet_checksum,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The standard check input, whose checksums are given in every CRC catalogue
+    const CHECK: &[u8] = b"123456789";
+
+    /// A CRC unit which calculates in software, for testing `Hardware`
+    struct Software<K>(K);
+
+    impl<K: Checksum> CrcPeripheral for Software<K> {
+        fn reset(&mut self) {
+            self.0.reset()
+        }
+
+        fn feed(&mut self, data: &[u8]) {
+            self.0.update(data)
+        }
+
+        fn result(&mut self) -> CRC {
+            self.0.value()
+        }
+    }
+
+    fn checksum<K: Checksum>(mut k: K, data: &[u8]) -> CRC {
+        k.reset();
+        k.update(data);
+        k.value()
+    }
+
+    /// Every byte value, and enough bytes to cycle through the tables several times
+    fn long_input() -> [u8; 1000] {
+        let mut data = [0; 1000];
+        for (i, d) in data.iter_mut().enumerate() {
+            *d = (i * 7 + i / 256) as u8;
+        }
+        data
+    }
+
+    fn check<K: Checksum>(new: impl Fn() -> K, expected: CRC) {
+        assert_eq!(checksum(new(), CHECK), expected);
+
+        // Feeding the input in pieces gives the same result
+        let data = long_input();
+        let whole = checksum(new(), &data);
+        let mut k = new();
+        k.reset();
+        for chunk in data.chunks(13) {
+            k.update(chunk);
+        }
+        assert_eq!(k.value(), whole);
+
+        // As does starting again after a reset
+        k.reset();
+        k.update(CHECK);
+        assert_eq!(k.value(), expected);
+    }
+
+    fn same<K: Checksum, L: Checksum>(a: impl Fn() -> K, b: impl Fn() -> L) {
+        let data = long_input();
+        for len in [0, 1, 2, 3, 9, 100, data.len()] {
+            let data = &data[..len];
+            assert_eq!(checksum(a(), data), checksum(b(), data));
+        }
+    }
+
+    #[test]
+    fn crc8() {
+        check(Crc8::default, 0xF4);
+        check(bitwise::Crc8::default, 0xF4);
+        check(nibble::Crc8::default, 0xF4);
+        check(|| Hardware::<_, 1>(Software(Crc8::default())), 0xF4);
+        same(Crc8::default, bitwise::Crc8::default);
+        same(Crc8::default, nibble::Crc8::default);
+    }
+
+    #[test]
+    fn crc16() {
+        check(Crc16Ccitt::default, 0x906E);
+        check(bitwise::Crc16Ccitt::default, 0x906E);
+        check(nibble::Crc16Ccitt::default, 0x906E);
+        check(|| Hardware::<_, 2>(Software(Crc16Ccitt::default())), 0x906E);
+        same(Crc16Ccitt::default, bitwise::Crc16Ccitt::default);
+        same(Crc16Ccitt::default, nibble::Crc16Ccitt::default);
+    }
+
+    #[test]
+    fn crc32() {
+        check(Crc32::default, 0xCBF4_3926);
+        check(bitwise::Crc32::default, 0xCBF4_3926);
+        check(nibble::Crc32::default, 0xCBF4_3926);
+        check(|| Hardware::<_, 4>(Software(Crc32::default())), 0xCBF4_3926);
+        same(Crc32::default, bitwise::Crc32::default);
+        same(Crc32::default, nibble::Crc32::default);
+    }
+
+    #[test]
+    fn bytes() {
+        assert_eq!(to_bytes(0x1234_5678), [0x78, 0x56, 0x34, 0x12]);
+        assert_eq!(from_bytes::<Crc16Ccitt>(&[0x78, 0x56, 0x34, 0x12]), 0x5678);
+        assert_eq!(truncate::<Crc8>(0x1234_5678), 0x78);
+        assert_eq!(truncate::<Crc32>(0x1234_5678), 0x1234_5678);
+    }
+}
@@ -0,0 +1,103 @@
+//! Table-free checksums, calculated a bit at a time. These are the slowest, but need no flash
+//! for lookup tables, so suit the smallest MCUs.
+
+use super::{Checksum, CRC};
+
+/// CRC-8/SMBUS, as `crc::Crc8`
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Crc8(u8);
+
+impl Checksum for Crc8 {
+    const LEN: usize = 1;
+
+    fn reset(&mut self) {
+        self.0 = 0;
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for d in data {
+            self.0 ^= d;
+            for _ in 0..8 {
+                self.0 = if self.0 & 0x80 != 0 {
+                    (self.0 << 1) ^ super::CRC8_POLY
+                } else {
+                    self.0 << 1
+                };
+            }
+        }
+    }
+
+    fn value(&mut self) -> CRC {
+        CRC::from(self.0)
+    }
+}
+
+/// CRC-16/X-25, as `crc::Crc16Ccitt`
+#[derive(Clone, Copy, Debug)]
+pub struct Crc16Ccitt(u16);
+
+impl Default for Crc16Ccitt {
+    fn default() -> Self {
+        Crc16Ccitt(!0)
+    }
+}
+
+impl Checksum for Crc16Ccitt {
+    const LEN: usize = 2;
+
+    fn reset(&mut self) {
+        self.0 = !0;
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for d in data {
+            self.0 ^= u16::from(*d);
+            for _ in 0..8 {
+                self.0 = if self.0 & 1 != 0 {
+                    (self.0 >> 1) ^ super::CRC16_POLY
+                } else {
+                    self.0 >> 1
+                };
+            }
+        }
+    }
+
+    fn value(&mut self) -> CRC {
+        CRC::from(!self.0)
+    }
+}
+
+/// CRC-32, as `crc::Crc32`
+#[derive(Clone, Copy, Debug)]
+pub struct Crc32(u32);
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Crc32(!0)
+    }
+}
+
+impl Checksum for Crc32 {
+    const LEN: usize = 4;
+
+    fn reset(&mut self) {
+        self.0 = !0;
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for d in data {
+            self.0 ^= u32::from(*d);
+            for _ in 0..8 {
+                self.0 = if self.0 & 1 != 0 {
+                    (self.0 >> 1) ^ super::CRC32_POLY
+                } else {
+                    self.0 >> 1
+                };
+            }
+        }
+    }
+
+    fn value(&mut self) -> CRC {
+        !self.0
+    }
+}
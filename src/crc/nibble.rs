@@ -0,0 +1,148 @@
+//! Checksums calculated four bits at a time, with a 16 entry lookup table. A compromise between
+//! the speed of the full tables and the size of `crc::bitwise`.
+
+use super::{Checksum, CRC};
+
+/// CRC-8/SMBUS, as `crc::Crc8`
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Crc8(u8);
+
+const CRC8_TABLE: [u8; 16] = {
+    let mut table = [0; 16];
+    let mut i = 0;
+    while i < 16 {
+        let mut crc = (i as u8) << 4;
+        let mut bit = 0;
+        while bit < 4 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ super::CRC8_POLY
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+impl Checksum for Crc8 {
+    const LEN: usize = 1;
+
+    fn reset(&mut self) {
+        self.0 = 0;
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for d in data {
+            self.0 ^= d;
+            self.0 = (self.0 << 4) ^ CRC8_TABLE[usize::from(self.0 >> 4)];
+            self.0 = (self.0 << 4) ^ CRC8_TABLE[usize::from(self.0 >> 4)];
+        }
+    }
+
+    fn value(&mut self) -> CRC {
+        CRC::from(self.0)
+    }
+}
+
+/// CRC-16/X-25, as `crc::Crc16Ccitt`
+#[derive(Clone, Copy, Debug)]
+pub struct Crc16Ccitt(u16);
+
+const CRC16_TABLE: [u16; 16] = {
+    let mut table = [0; 16];
+    let mut i = 0;
+    while i < 16 {
+        let mut crc = i as u16;
+        let mut bit = 0;
+        while bit < 4 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ super::CRC16_POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+impl Default for Crc16Ccitt {
+    fn default() -> Self {
+        Crc16Ccitt(!0)
+    }
+}
+
+impl Checksum for Crc16Ccitt {
+    const LEN: usize = 2;
+
+    fn reset(&mut self) {
+        self.0 = !0;
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for d in data {
+            self.0 ^= u16::from(*d);
+            self.0 = (self.0 >> 4) ^ CRC16_TABLE[usize::from(self.0 & 0xF)];
+            self.0 = (self.0 >> 4) ^ CRC16_TABLE[usize::from(self.0 & 0xF)];
+        }
+    }
+
+    fn value(&mut self) -> CRC {
+        CRC::from(!self.0)
+    }
+}
+
+/// CRC-32, as `crc::Crc32`
+#[derive(Clone, Copy, Debug)]
+pub struct Crc32(u32);
+
+const CRC32_TABLE: [u32; 16] = {
+    let mut table = [0; 16];
+    let mut i = 0;
+    while i < 16 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 4 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ super::CRC32_POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Crc32(!0)
+    }
+}
+
+impl Checksum for Crc32 {
+    const LEN: usize = 4;
+
+    fn reset(&mut self) {
+        self.0 = !0;
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for d in data {
+            self.0 ^= u32::from(*d);
+            self.0 = (self.0 >> 4) ^ CRC32_TABLE[(self.0 & 0xF) as usize];
+            self.0 = (self.0 >> 4) ^ CRC32_TABLE[(self.0 & 0xF) as usize];
+        }
+    }
+
+    fn value(&mut self) -> CRC {
+        !self.0
+    }
+}
@@ -0,0 +1,203 @@
+//! Receiver-side address filtering.
+
+use crate::{Addr, Flags, Packet, Raw, BROADCAST};
+
+/// What should be done with a received packet
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Route {
+    /// The packet is for this node, and should be handled
+    pub accept: bool,
+    /// The packet should be passed on to the next node in a daisy chain
+    pub forward: bool,
+}
+
+/// Counts of packets seen by an `Endpoint`. The counts wrap around on overflow.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct Stats {
+    /// Packets addressed to this node, including broadcasts
+    pub accepted: u32,
+    /// Packets passed on to the next node
+    pub forwarded: u32,
+    /// Packets neither accepted nor forwarded
+    pub dropped: u32,
+}
+
+/// A node on the bus, which only accepts packets addressed to it or broadcast. Packets with
+/// `Flags::IGNORE` set are never accepted, but are still forwarded.
+#[derive(Clone, Debug)]
+pub struct Endpoint {
+    addr: Option<Addr>,
+    forward: bool,
+    stats: Stats,
+}
+
+impl Endpoint {
+    /// Create an endpoint for the given address
+    pub fn new(addr: Addr) -> Self {
+        Endpoint {
+            addr: Some(addr),
+            forward: false,
+            stats: Stats::default(),
+        }
+    }
+
+    /// Create an endpoint for a module which has not been assigned an address yet. Only
+    /// broadcasts are accepted.
+    pub fn unaddressed() -> Self {
+        Endpoint {
+            addr: None,
+            forward: false,
+            stats: Stats::default(),
+        }
+    }
+
+    /// Pass on packets for other addresses, and broadcasts, to the next node in a daisy chain
+    pub fn with_forwarding(mut self, forward: bool) -> Self {
+        self.forward = forward;
+        self
+    }
+
+    pub fn addr(&self) -> Option<Addr> {
+        self.addr
+    }
+
+    /// Change the address, e.g. once one has been assigned by the controller
+    pub fn set_addr(&mut self, addr: Addr) {
+        self.addr = Some(addr);
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = Stats::default();
+    }
+
+    /// Decide what to do with a received packet, and update the statistics
+    pub fn route<D, const N: usize>(&mut self, packet: &Packet<D, N>) -> Route {
+        let route = match packet.target {
+            _ if packet.flags.contains(Flags::IGNORE) => Route {
+                accept: false,
+                forward: self.forward,
+            },
+            BROADCAST => Route {
+                accept: true,
+                forward: self.forward,
+            },
+            target if Some(target) == self.addr => Route {
+                accept: true,
+                forward: false,
+            },
+            _ => Route {
+                accept: false,
+                forward: self.forward,
+            },
+        };
+
+        if route.accept {
+            self.stats.accepted = self.stats.accepted.wrapping_add(1);
+        }
+        if route.forward {
+            self.stats.forwarded = self.stats.forwarded.wrapping_add(1);
+        }
+        if !route.accept && !route.forward {
+            self.stats.dropped = self.stats.dropped.wrapping_add(1);
+        }
+        route
+    }
+
+    /// Returns the packet if it is addressed to this node. For use when not forwarding.
+    pub fn accept<const N: usize>(
+        &mut self,
+        packet: Packet<Raw<N>, N>,
+    ) -> Option<Packet<Raw<N>, N>> {
+        if self.route(&packet).accept {
+            Some(packet)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(target: Addr) -> Packet<Raw> {
+        Packet::command(target, Raw::new())
+    }
+
+    const ACCEPT: Route = Route {
+        accept: true,
+        forward: false,
+    };
+    const FORWARD: Route = Route {
+        accept: false,
+        forward: true,
+    };
+    const DROP: Route = Route {
+        accept: false,
+        forward: false,
+    };
+
+    #[test]
+    fn routes() {
+        let mut endpoint = Endpoint::new(Addr(3));
+        assert_eq!(endpoint.route(&packet(Addr(3))), ACCEPT);
+        assert_eq!(endpoint.route(&packet(BROADCAST)), ACCEPT);
+        assert_eq!(endpoint.route(&packet(Addr(4))), DROP);
+        let ignored = packet(Addr(3)).with_flags(Flags::IGNORE);
+        assert_eq!(endpoint.route(&ignored), DROP);
+        let expected = Stats {
+            accepted: 2,
+            forwarded: 0,
+            dropped: 2,
+        };
+        assert_eq!(endpoint.stats(), expected);
+        endpoint.reset_stats();
+        assert_eq!(endpoint.stats(), Stats::default());
+    }
+
+    #[test]
+    fn forwarding() {
+        let mut endpoint = Endpoint::new(Addr(3)).with_forwarding(true);
+        assert_eq!(endpoint.route(&packet(Addr(3))), ACCEPT);
+        // Broadcasts are for the rest of the chain as well
+        let both = Route {
+            accept: true,
+            forward: true,
+        };
+        assert_eq!(endpoint.route(&packet(BROADCAST)), both);
+        assert_eq!(endpoint.route(&packet(Addr(4))), FORWARD);
+        let ignored = packet(Addr(3)).with_flags(Flags::IGNORE);
+        assert_eq!(endpoint.route(&ignored), FORWARD);
+        let expected = Stats {
+            accepted: 2,
+            forwarded: 3,
+            dropped: 0,
+        };
+        assert_eq!(endpoint.stats(), expected);
+    }
+
+    #[test]
+    fn unaddressed() {
+        let mut endpoint = Endpoint::unaddressed();
+        assert_eq!(endpoint.addr(), None);
+        assert!(endpoint.accept(packet(BROADCAST)).is_some());
+        assert!(endpoint.accept(packet(Addr(3))).is_none());
+        let ignored = packet(BROADCAST).with_flags(Flags::IGNORE);
+        assert!(endpoint.accept(ignored).is_none());
+
+        // Until an address is assigned
+        endpoint.set_addr(Addr(3));
+        assert_eq!(endpoint.addr(), Some(Addr(3)));
+        assert!(endpoint.accept(packet(Addr(3))).is_some());
+        let expected = Stats {
+            accepted: 2,
+            forwarded: 0,
+            dropped: 2,
+        };
+        assert_eq!(endpoint.stats(), expected);
+    }
+}
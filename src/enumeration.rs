@@ -0,0 +1,382 @@
+//! Dynamic address assignment.
+//!
+//! The controller broadcasts `Command::Discover`. Each module which does not have an address
+//! yet waits for a pseudo-random time within the discovery window, so that modules are
+//! unlikely to answer at once, then answers with `Response::Announce` carrying its unique ID.
+//! The controller looks the ID up in its `AddressTable`, allocating a new address if it hasn't
+//! seen the module before, and broadcasts `Command::AssignAddress`.
+//!
+//! Discovery rounds are repeated until one passes with no announcements and no corrupted
+//! packets, since a corrupted packet usually means two modules answered at the same time.
+//!
+//! As with `reliable::Channel`, neither side does any I/O itself.
+
+use heapless as h;
+
+use crate::command::{Command, Response};
+use crate::reliable::Clock;
+use crate::{Addr, Packet, PacketType, Raw, BROADCAST, CONTROLLER};
+
+/// Unique ID of a module, e.g. its serial number or a random nonce chosen at startup
+pub type Uid = u32;
+
+/// Maps module IDs to the addresses assigned to them. The entries can be saved, e.g. to
+/// EEPROM, so that modules get the same addresses every time the synth is switched on.
+#[derive(Clone, Debug)]
+pub struct AddressTable<const N: usize> {
+    entries: h::Vec<(Uid, Addr), N>,
+}
+
+impl<const N: usize> AddressTable<N> {
+    pub fn new() -> Self {
+        AddressTable {
+            entries: h::Vec::new(),
+        }
+    }
+
+    /// Restore a saved table. Entries with reserved or duplicate addresses, and any which don't
+    /// fit, are skipped.
+    pub fn from_entries(entries: &[(Uid, Addr)]) -> Self {
+        let mut table = Self::new();
+        for &(uid, addr) in entries {
+            if Addr::new(addr.get()).is_some() && table.uid(addr).is_none() {
+                table.remove(uid);
+                let _ = table.entries.push((uid, addr));
+            }
+        }
+        table
+    }
+
+    /// The current assignments, in the order they were made
+    pub fn entries(&self) -> &[(Uid, Addr)] {
+        &self.entries
+    }
+
+    /// The address assigned to a module
+    pub fn get(&self, uid: Uid) -> Option<Addr> {
+        self.entries.iter().find(|e| e.0 == uid).map(|e| e.1)
+    }
+
+    /// The module an address is assigned to
+    pub fn uid(&self, addr: Addr) -> Option<Uid> {
+        self.entries.iter().find(|e| e.1 == addr).map(|e| e.0)
+    }
+
+    /// The address assigned to a module, allocating the lowest free address if it doesn't have
+    /// one yet. Returns `None` if the table is full, or every address is taken.
+    pub fn assign(&mut self, uid: Uid) -> Option<Addr> {
+        if let Some(addr) = self.get(uid) {
+            return Some(addr);
+        }
+        let addr = (1..BROADCAST.get())
+            .map(Addr)
+            .find(|a| self.uid(*a).is_none())?;
+        self.entries.push((uid, addr)).ok()?;
+        Some(addr)
+    }
+
+    /// Forget a module, freeing its address
+    pub fn remove(&mut self, uid: Uid) -> Option<Addr> {
+        let i = self.entries.iter().position(|e| e.0 == uid)?;
+        Some(self.entries.remove(i).1)
+    }
+}
+
+impl<const N: usize> Default for AddressTable<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Discovery settings
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    /// Modules answer at a random time within this window. A round lasts twice as long, to
+    /// leave time for the last answers to arrive.
+    pub window_ms: u16,
+    /// Give up after this many rounds, even if modules are still answering
+    pub max_rounds: u8,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            window_ms: 100,
+            max_rounds: 8,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Round {
+    Idle,
+    Starting,
+    Listening { since: u32 },
+}
+
+/// Controller side of enumeration
+pub struct Enumerator<C, const N: usize> {
+    clock: C,
+    config: Config,
+    table: AddressTable<N>,
+    round: Round,
+    rounds: u8,
+    progress: bool,
+    assignments: h::Deque<(Uid, Addr), 8>,
+}
+
+impl<C: Clock, const N: usize> Enumerator<C, N> {
+    /// Create an enumerator which assigns addresses from `table`
+    pub fn new(table: AddressTable<N>, clock: C, config: Config) -> Self {
+        Enumerator {
+            clock,
+            config,
+            table,
+            round: Round::Idle,
+            rounds: 0,
+            progress: false,
+            assignments: h::Deque::new(),
+        }
+    }
+
+    /// Start discovering modules
+    pub fn start(&mut self) {
+        self.round = Round::Starting;
+        self.rounds = 0;
+    }
+
+    /// Returns true once enumeration has finished, or before it has been started
+    pub fn is_done(&self) -> bool {
+        matches!(self.round, Round::Idle) && self.assignments.is_empty()
+    }
+
+    pub fn table(&self) -> &AddressTable<N> {
+        &self.table
+    }
+
+    pub fn into_table(self) -> AddressTable<N> {
+        self.table
+    }
+
+    /// Returns the next packet to write to the link, if any
+    pub fn poll_transmit<const P: usize>(&mut self) -> Option<Packet<Raw<P>, P>> {
+        if let Some((uid, addr)) = self.assignments.pop_front() {
+            let command = Command::AssignAddress { uid, addr };
+            return Some(Packet::command_sized(BROADCAST, command).encoded());
+        }
+
+        let now = self.clock.now_ms();
+        match self.round {
+            Round::Listening { since }
+                if now.wrapping_sub(since) >= 2 * u32::from(self.config.window_ms) =>
+            {
+                if self.progress && self.rounds < self.config.max_rounds {
+                    self.round = Round::Starting;
+                    self.poll_transmit()
+                } else {
+                    self.round = Round::Idle;
+                    None
+                }
+            }
+            Round::Starting => {
+                self.round = Round::Listening { since: now };
+                self.rounds += 1;
+                self.progress = false;
+                let command = Command::Discover {
+                    window_ms: self.config.window_ms,
+                };
+                Some(Packet::command_sized(BROADCAST, command).encoded())
+            }
+            _ => None,
+        }
+    }
+
+    /// Handle a packet received from the link. Returns true if it was an announcement, which
+    /// needs no further handling.
+    pub fn receive<const P: usize>(&mut self, packet: &Packet<Raw<P>, P>) -> bool {
+        let uid = match packet.decoded::<Response>().map(Packet::into_data) {
+            Ok(Response::Announce { uid }) if packet.typ == PacketType::Command => uid,
+            _ => return false,
+        };
+        if let Some(addr) = self.table.assign(uid) {
+            // If this is full the module will answer again next round
+            let _ = self.assignments.push_back((uid, addr));
+            self.progress = true;
+        }
+        true
+    }
+
+    /// Report that a corrupted packet was received, which probably means that two modules
+    /// answered at once.
+    pub fn reject(&mut self) {
+        if let Round::Listening { .. } = self.round {
+            self.progress = true;
+        }
+    }
+}
+
+/// Module side of enumeration
+pub struct Responder<C> {
+    clock: C,
+    uid: Uid,
+    addr: Option<Addr>,
+    rng: u32,
+    reply_at: Option<u32>,
+}
+
+impl<C: Clock> Responder<C> {
+    /// Create a responder for a module which doesn't have an address yet
+    pub fn new(uid: Uid, clock: C) -> Self {
+        Responder {
+            clock,
+            uid,
+            addr: None,
+            // xorshift gets stuck at 0
+            rng: if uid == 0 { 0x1234_5678 } else { uid },
+            reply_at: None,
+        }
+    }
+
+    /// The address assigned by the controller, if any
+    pub fn address(&self) -> Option<Addr> {
+        self.addr
+    }
+
+    /// Handle a packet received from the link. Returns true if it was an enumeration command,
+    /// which needs no further handling.
+    pub fn receive<const P: usize>(&mut self, packet: &Packet<Raw<P>, P>) -> bool {
+        if packet.typ != PacketType::Command {
+            return false;
+        }
+        match packet.decoded::<Command>().map(Packet::into_data) {
+            Ok(Command::Discover { window_ms }) => {
+                if self.addr.is_none() {
+                    let delay = match window_ms {
+                        0 => 0,
+                        w => self.random() % u32::from(w),
+                    };
+                    self.reply_at = Some(self.clock.now_ms().wrapping_add(delay));
+                }
+                true
+            }
+            Ok(Command::AssignAddress { uid, addr }) => {
+                if uid == self.uid {
+                    if let Some(addr) = Addr::new(addr.get()) {
+                        self.addr = Some(addr);
+                        self.reply_at = None;
+                    }
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the next packet to write to the link, if any
+    pub fn poll_transmit<const P: usize>(&mut self) -> Option<Packet<Raw<P>, P>> {
+        let reply_at = self.reply_at?;
+        // Treat times more than half the clock range in the future as being in the past
+        if self.clock.now_ms().wrapping_sub(reply_at) > u32::MAX / 2 {
+            return None;
+        }
+        self.reply_at = None;
+        let response = Response::Announce { uid: self.uid };
+        // There's no address to send from yet
+        let packet = Packet::response_sized(CONTROLLER, response).with_source(BROADCAST);
+        Some(packet.encoded())
+    }
+
+    fn random(&mut self) -> u32 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+        self.rng
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{self, Bus, Faults};
+    use std::vec::Vec;
+
+    fn responders(bus: &Bus, uids: &[Uid]) -> Vec<Responder<Bus>> {
+        uids.iter()
+            .map(|&uid| Responder::new(uid, bus.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn collisions_back_off() {
+        let bus = Bus::new(4, Faults::default(), 1);
+        let config = Config {
+            window_ms: 4,
+            max_rounds: 20,
+        };
+        let mut enumerator = Enumerator::<_, 8>::new(AddressTable::new(), bus.clone(), config);
+        let mut responders = responders(&bus, &[11, 22, 33, 44]);
+        let collisions = sim::enumerate(&bus, &mut enumerator, &mut responders);
+
+        // Modules which collided answered again at a different time in a later round
+        assert!(collisions > 0);
+        assert!(enumerator.rounds > 2);
+        assert!(enumerator.rounds < config.max_rounds);
+        for r in &responders {
+            let addr = r.address();
+            assert!(addr.is_some());
+            assert_eq!(enumerator.table().get(r.uid), addr);
+        }
+    }
+
+    #[test]
+    fn rounds_repeat_until_no_progress() {
+        let bus = Bus::new(3, Faults::default(), 1);
+        let config = Config {
+            window_ms: 100,
+            max_rounds: 8,
+        };
+        let mut enumerator = Enumerator::<_, 8>::new(AddressTable::new(), bus.clone(), config);
+        let mut responders = responders(&bus, &[11, 22, 33]);
+        assert_eq!(sim::enumerate(&bus, &mut enumerator, &mut responders), 0);
+        // Everything is found in the first round, and the second finds nothing more
+        assert_eq!(enumerator.rounds, 2);
+        assert_eq!(enumerator.table().entries().len(), 3);
+
+        // Corrupted packets count as progress, up to the limit on rounds
+        let mut enumerator = Enumerator::<_, 8>::new(AddressTable::new(), bus.clone(), config);
+        enumerator.start();
+        let mut discovers = 0;
+        while !enumerator.is_done() {
+            if enumerator.poll_transmit::<32>().is_some() {
+                discovers += 1;
+            }
+            enumerator.reject();
+            bus.advance(1);
+        }
+        assert_eq!(discovers, config.max_rounds);
+    }
+
+    #[test]
+    fn table_from_entries() {
+        let entries = [
+            (1, Addr(3)),
+            (2, CONTROLLER),
+            (3, BROADCAST),
+            // Duplicate address, so skipped
+            (4, Addr(3)),
+            (5, Addr(7)),
+            // Duplicate module, so it moves
+            (5, Addr(8)),
+            (6, Addr(9)),
+        ];
+        let table = AddressTable::<3>::from_entries(&entries);
+        assert_eq!(table.entries(), &[(1, Addr(3)), (5, Addr(8)), (6, Addr(9))]);
+        assert_eq!(table.uid(Addr(7)), None);
+        assert_eq!(table.get(4), None);
+
+        // A module which lost its saved entry gets the lowest free address
+        let mut table = AddressTable::<4>::from_entries(&entries);
+        assert_eq!(table.assign(4), Some(Addr(1)));
+        assert_eq!(table.assign(1), Some(Addr(3)));
+    }
+}
@@ -0,0 +1,363 @@
+//! Fragmentation of messages too long for a single packet.
+//!
+//! A message is split into fragments of up to `FRAGMENT_LEN` bytes, or two less than the packet
+//! capacity if that isn't `PACKET_LEN`, each sent in its own packet with `Flags::IS_FRAGMENT`
+//! set. Every fragment but the last also has `Flags::MORE_FRAGMENTS` set. The payload of each
+//! fragment starts with a message ID, chosen by the sender, and the index of the fragment
+//! within the message.
+//!
+//! On the receiving side, a `Reassembler` collects the fragments, which may arrive out of order
+//! or more than once, and hands back the complete message. As with `reliable::Channel`, neither
+//! side does any I/O itself.
+
+use heapless as h;
+
+use crate::reliable::Clock;
+use crate::{Addr, Error, Flags, Packet, PacketType, Raw, PACKET_LEN};
+
+/// Number of message bytes carried by each fragment, after the message ID and index
+pub const FRAGMENT_LEN: usize = fragment_len(PACKET_LEN);
+
+/// Longest message which can be fragmented, as fragment indexes are a single byte
+pub const MAX_MESSAGE_LEN: usize = max_message_len(PACKET_LEN);
+
+const fn fragment_len(capacity: usize) -> usize {
+    capacity.saturating_sub(2)
+}
+
+const fn max_message_len(capacity: usize) -> usize {
+    256 * fragment_len(capacity)
+}
+
+/// Iterator over the fragments of a message, returned by `Fragments::new`
+pub struct Fragments<'a, const N: usize = PACKET_LEN> {
+    packet: Packet<&'a [u8], N>,
+    id: u8,
+    index: usize,
+}
+
+impl<'a, const N: usize> Fragments<'a, N> {
+    /// Split a packet with a long payload into fragments. The type, flags and addresses of the
+    /// packet are copied to every fragment. `id` should differ from that of the last message
+    /// sent to the same target, so the receiver can tell them apart.
+    ///
+    /// Returns `None` if the payload is longer than `MAX_MESSAGE_LEN`, or the equivalent for
+    /// packets of `N` bytes.
+    pub fn new(packet: Packet<&'a [u8], N>, id: u8) -> Option<Self> {
+        if fragment_len(N) == 0 || packet.data.len() > max_message_len(N) {
+            return None;
+        }
+        Some(Fragments {
+            packet,
+            id,
+            index: 0,
+        })
+    }
+}
+
+impl<const N: usize> Iterator for Fragments<'_, N> {
+    type Item = Packet<Raw<N>, N>;
+
+    fn next(&mut self) -> Option<Packet<Raw<N>, N>> {
+        let message = self.packet.data;
+        let start = self.index * fragment_len(N);
+        // An empty message is still sent as a single, empty, fragment
+        if start > message.len() || (start == message.len() && self.index > 0) {
+            return None;
+        }
+        let end = message.len().min(start + fragment_len(N));
+
+        let mut data = Raw::new();
+        // These fit, as the fragment length leaves room for the ID and index
+        let _ = data.extend_from_slice(&[self.id, self.index as u8]);
+        let _ = data.extend_from_slice(&message[start..end]);
+        let mut flags = self.packet.flags | Flags::IS_FRAGMENT;
+        flags.set(Flags::MORE_FRAGMENTS, end < message.len());
+        self.index += 1;
+
+        Some(Packet {
+            typ: self.packet.typ,
+            flags,
+            target: self.packet.target,
+            source: self.packet.source,
+            seq: self.packet.seq,
+            data,
+        })
+    }
+}
+
+/// Reassembly settings
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    /// A partly received message is abandoned if no new fragment of it arrives within this time
+    pub timeout_ms: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config { timeout_ms: 500 }
+    }
+}
+
+/// A message which is being reassembled
+struct Partial {
+    typ: PacketType,
+    flags: Flags,
+    target: Addr,
+    source: Addr,
+    id: u8,
+    /// Bitmap of the fragments received so far
+    received: [u32; 8],
+    /// Number of fragments and length of the message, once the last fragment has arrived
+    last: Option<(usize, usize)>,
+    updated_at: u32,
+}
+
+impl Partial {
+    fn has(&self, index: usize) -> bool {
+        self.received[index / 32] & (1 << (index % 32)) != 0
+    }
+}
+
+/// Reassembles messages of up to `N` bytes from their fragments. The fragments may be sent in
+/// packets of any capacity, so long as it is the same for every fragment of a message.
+///
+/// One message is reassembled at a time. A fragment of a different message, from any source,
+/// abandons the one in progress.
+pub struct Reassembler<C, const N: usize> {
+    clock: C,
+    config: Config,
+    partial: Option<Partial>,
+    buf: h::Vec<u8, N>,
+    /// Source and ID of the last message completed, so late duplicates of its fragments can
+    /// be ignored
+    completed: Option<(Addr, u8)>,
+}
+
+impl<C: Clock, const N: usize> Reassembler<C, N> {
+    pub fn new(clock: C, config: Config) -> Self {
+        Reassembler {
+            clock,
+            config,
+            partial: None,
+            buf: h::Vec::new(),
+            completed: None,
+        }
+    }
+
+    /// Handle a packet received from the link. Returns the complete message once its last
+    /// missing fragment has arrived, as a packet of up to `N` bytes with the type, flags and
+    /// addresses of the fragments. Packets which aren't fragments, and duplicate fragments, are
+    /// ignored.
+    ///
+    /// Returns `Error::PayloadTooLong` if the message is longer than `N` bytes, in which case
+    /// it is abandoned, or `Error::Framing` for a malformed fragment.
+    pub fn receive<const P: usize>(
+        &mut self,
+        packet: &Packet<Raw<P>, P>,
+    ) -> Result<Option<Packet<&[u8], N>>, Error> {
+        if !packet.flags.contains(Flags::IS_FRAGMENT) {
+            return Ok(None);
+        }
+        let (id, index, data) = match packet.data.as_slice() {
+            [id, index, data @ ..] => (*id, usize::from(*index), data),
+            _ => return Err(Error::Framing),
+        };
+        if self.completed == Some((packet.source, id)) {
+            return Ok(None);
+        }
+
+        let now = self.clock.now_ms();
+        let partial = match &mut self.partial {
+            Some(p) if p.source == packet.source && p.id == id => p,
+            partial => {
+                self.buf.clear();
+                partial.insert(Partial {
+                    typ: packet.typ,
+                    flags: packet.flags - Flags::IS_FRAGMENT - Flags::MORE_FRAGMENTS,
+                    target: packet.target,
+                    source: packet.source,
+                    id,
+                    received: [0; 8],
+                    last: None,
+                    updated_at: now,
+                })
+            }
+        };
+        if partial.has(index) {
+            return Ok(None);
+        }
+
+        // Every fragment but the last is full, so the offset follows from the index
+        let more = packet.flags.contains(Flags::MORE_FRAGMENTS);
+        let start = index * fragment_len(P);
+        let end = start + data.len();
+        let beyond_last = matches!(partial.last, Some((count, _)) if index >= count);
+        if (more && data.len() != fragment_len(P)) || beyond_last {
+            return Err(Error::Framing);
+        }
+        if end > N {
+            self.partial = None;
+            return Err(Error::PayloadTooLong(end));
+        }
+        if self.buf.len() < end {
+            // Can't fail, as end <= N
+            let _ = self.buf.resize(end, 0);
+        }
+        self.buf[start..end].copy_from_slice(data);
+        partial.received[index / 32] |= 1 << (index % 32);
+        partial.updated_at = now;
+        if !more {
+            partial.last = Some((index + 1, end));
+        }
+
+        match partial.last {
+            Some((count, len)) if (0..count).all(|i| partial.has(i)) => {
+                let message = Packet {
+                    typ: partial.typ,
+                    flags: partial.flags,
+                    target: partial.target,
+                    source: partial.source,
+                    seq: 0,
+                    data: &self.buf[..len],
+                };
+                self.completed = Some((partial.source, partial.id));
+                self.partial = None;
+                Ok(Some(message))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns `Error::Timeout` once, if the message being reassembled has not been completed
+    /// in time. It is then abandoned.
+    pub fn poll(&mut self) -> Result<(), Error> {
+        let now = self.clock.now_ms();
+        match &self.partial {
+            Some(p) if now.wrapping_sub(p.updated_at) >= self.config.timeout_ms => {
+                self.partial = None;
+                Err(Error::Timeout)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns true if no message is being reassembled
+    pub fn is_idle(&self) -> bool {
+        self.partial.is_none()
+    }
+
+    /// Abandon the message being reassembled
+    pub fn reset(&mut self) {
+        self.partial = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::Cell;
+    use std::vec::Vec;
+
+    use super::*;
+
+    /// A message split over three fragments, in packets of 16 bytes
+    const MESSAGE: [u8; 40] = {
+        let mut message = [0; 40];
+        let mut i = 0;
+        while i < message.len() {
+            message[i] = i as u8;
+            i += 1;
+        }
+        message
+    };
+
+    const CONFIG: Config = Config { timeout_ms: 10 };
+
+    fn fragments(id: u8) -> Vec<Packet<Raw<16>, 16>> {
+        let packet = Packet::<_, 16>::command_sized(Addr(2), &MESSAGE[..]).with_source(Addr(1));
+        Fragments::new(packet, id).unwrap().collect()
+    }
+
+    #[test]
+    fn split() {
+        let fragments = fragments(5);
+        assert_eq!(fragments.len(), 3);
+        for (i, f) in fragments.iter().enumerate() {
+            assert_eq!(&f.data()[..2], &[5, i as u8]);
+            assert!(f.flags().contains(Flags::IS_FRAGMENT));
+            assert_eq!(f.flags().contains(Flags::MORE_FRAGMENTS), i < 2);
+        }
+    }
+
+    #[test]
+    fn out_of_order() {
+        let fragments = fragments(5);
+        let mut reassembler = Reassembler::<_, 64>::new(|| 0, CONFIG);
+        assert!(reassembler.receive(&fragments[2]).unwrap().is_none());
+        assert!(reassembler.receive(&fragments[0]).unwrap().is_none());
+        let message = reassembler.receive(&fragments[1]).unwrap().unwrap();
+        assert_eq!(message.data(), &&MESSAGE[..]);
+        assert_eq!(message.packet_type(), PacketType::Command);
+        assert_eq!(message.flags(), Flags::empty());
+        assert_eq!(message.target(), Addr(2));
+        assert_eq!(message.source(), Addr(1));
+
+        // The message is longer than the fragments, but fits a packet of the reassembled size
+        let mut buf = [0; 64];
+        assert!(message.encode_into(&mut buf).is_ok());
+        assert!(reassembler.is_idle());
+    }
+
+    #[test]
+    fn duplicates() {
+        let next = fragments(6);
+        let fragments = fragments(5);
+        let mut reassembler = Reassembler::<_, 64>::new(|| 0, CONFIG);
+        assert!(reassembler.receive(&fragments[0]).unwrap().is_none());
+        assert!(reassembler.receive(&fragments[0]).unwrap().is_none());
+        assert!(reassembler.receive(&fragments[1]).unwrap().is_none());
+        assert!(reassembler.receive(&fragments[2]).unwrap().is_some());
+
+        // Late duplicates of the completed message are ignored, but a new one is accepted
+        assert!(reassembler.receive(&fragments[1]).unwrap().is_none());
+        assert!(reassembler.is_idle());
+        assert!(reassembler.receive(&next[0]).unwrap().is_none());
+        assert!(!reassembler.is_idle());
+    }
+
+    #[test]
+    fn missing_fragment_times_out() {
+        let fragments = fragments(5);
+        let now = Cell::new(0);
+        let mut reassembler = Reassembler::<_, 64>::new(|| now.get(), CONFIG);
+        assert!(reassembler.receive(&fragments[0]).unwrap().is_none());
+        now.set(5);
+        assert!(reassembler.receive(&fragments[2]).unwrap().is_none());
+
+        // The timeout runs from the last fragment received
+        now.set(14);
+        assert_eq!(reassembler.poll(), Ok(()));
+        now.set(15);
+        assert_eq!(reassembler.poll(), Err(Error::Timeout));
+        assert_eq!(reassembler.poll(), Ok(()));
+        assert!(reassembler.is_idle());
+
+        // The abandoned fragments don't count towards a retransmission
+        assert!(reassembler.receive(&fragments[1]).unwrap().is_none());
+        assert!(reassembler.receive(&fragments[2]).unwrap().is_none());
+        assert!(reassembler.receive(&fragments[0]).unwrap().is_some());
+    }
+
+    #[test]
+    fn too_long() {
+        let fragments = fragments(5);
+        let mut reassembler = Reassembler::<_, 32>::new(|| 0, CONFIG);
+        assert!(reassembler.receive(&fragments[0]).unwrap().is_none());
+        assert!(reassembler.receive(&fragments[1]).unwrap().is_none());
+        assert_eq!(
+            reassembler.receive(&fragments[2]).unwrap_err(),
+            Error::PayloadTooLong(40)
+        );
+        assert!(reassembler.is_idle());
+    }
+}
@@ -0,0 +1,1149 @@
+//! The wire format: how packets are delimited and checksummed on a serial link, for both
+//! blocking and non-blocking I/O.
+
+use core::convert::Infallible;
+
+use heapless as h;
+
+use crate::crc::{self, Checksum, Crc32, DigesterInput, DigesterOutput, MAX_CHECKSUM_LEN};
+use crate::io::{SerialRead, SerialWrite};
+use crate::packet::{Packet, PacketRef, PacketType, Raw, Version, MAX_HEADER_LEN, PACKET_LEN};
+use crate::Error;
+
+/// Layout of packets on the wire.
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub enum WireFormat {
+    /// Packets are SLIP-style byte stuffed and delimited by `FRAME_END`, so a receiver can
+    /// discard garbage and lock onto the next frame after a dropped or extra byte.
+    #[default]
+    Framed,
+    /// Packets are written back to back with no delimiters, as understood by older firmware.
+    Legacy,
+}
+
+/// Frame delimiter. Never appears inside a framed packet.
+pub(crate) const FRAME_END: u8 = 0xC0;
+/// Introduces an escaped `FRAME_END` or `FRAME_ESC` byte.
+pub(crate) const FRAME_ESC: u8 = 0xDB;
+/// Escaped `FRAME_END`
+pub(crate) const FRAME_ESC_END: u8 = 0xDC;
+/// Escaped `FRAME_ESC`
+pub(crate) const FRAME_ESC_ESC: u8 = 0xDD;
+
+impl Packet<Raw> {
+    /// Read in a raw packet of the default capacity, using the default wire format and header
+    /// version
+    pub fn read_raw<S: SerialRead>(s: S) -> Result<Self, Error<S::Error>> {
+        Self::read_raw_sized(s)
+    }
+
+    /// Read in a raw packet of the default capacity, using the given wire format and header
+    /// version.
+    ///
+    /// For the framed format, any bytes preceding the next frame delimiter are discarded.
+    pub fn read_raw_as<S: SerialRead>(
+        s: S,
+        format: WireFormat,
+        version: Version,
+    ) -> Result<Self, Error<S::Error>> {
+        Self::read_raw_as_sized(s, format, version)
+    }
+}
+
+impl<const N: usize> Packet<Raw<N>, N> {
+    /// Write out a raw packet to the stream, using the default wire format and header version
+    pub fn write_raw<S: SerialWrite>(&self, s: S) -> Result<(), Error<S::Error>> {
+        self.write_raw_as(s, WireFormat::default(), Version::default())
+    }
+
+    /// Write out a raw packet to the stream, using the given wire format and header version
+    pub fn write_raw_as<S: SerialWrite>(
+        &self,
+        s: S,
+        format: WireFormat,
+        version: Version,
+    ) -> Result<(), Error<S::Error>> {
+        self.write_raw_with(s, format, version, Crc32::default())
+    }
+
+    /// Write out a raw packet to the stream, using the given wire format, header version and
+    /// checksum
+    pub fn write_raw_with<S: SerialWrite, K: Checksum>(
+        &self,
+        s: S,
+        format: WireFormat,
+        version: Version,
+        checksum: K,
+    ) -> Result<(), Error<S::Error>> {
+        let header = self.header(version).map_err(Error::widen)?;
+        let mut out = DigesterOutput::new(s, format, checksum);
+        out.begin()?;
+        out.write_data(&header)?;
+        out.write_data(self.data.as_ref())?;
+        out.write_checksum()?;
+        out.end()?;
+
+        Ok(())
+    }
+
+    /// As `Packet::read_raw`, for packets of any capacity
+    pub fn read_raw_sized<S: SerialRead>(s: S) -> Result<Self, Error<S::Error>> {
+        Self::read_raw_as_sized(s, WireFormat::default(), Version::default())
+    }
+
+    /// As `Packet::read_raw_as`, for packets of any capacity
+    pub fn read_raw_as_sized<S: SerialRead>(
+        s: S,
+        format: WireFormat,
+        version: Version,
+    ) -> Result<Self, Error<S::Error>> {
+        Self::read_raw_with(s, format, version, Crc32::default())
+    }
+
+    /// Read in a raw packet of any capacity, using the given wire format, header version and
+    /// checksum
+    pub fn read_raw_with<S: SerialRead, K: Checksum>(
+        s: S,
+        format: WireFormat,
+        version: Version,
+        checksum: K,
+    ) -> Result<Self, Error<S::Error>> {
+        let mut input = DigesterInput::new(s, format, checksum);
+        input.sync()?;
+        let mut header: [u8; MAX_HEADER_LEN] = Default::default();
+        header[0] = input.read()?;
+        let layout = version.detect(header[0]).map_err(Error::widen)?;
+        let header = &mut header[..layout.header_len()];
+        input.read_data(&mut header[1..])?;
+        let (mut packet, len) = Self::from_header(header, layout).map_err(Error::widen)?;
+        // from_header checks that the payload fits
+        let _ = packet.data.resize(len, 0);
+        input.read_data(&mut packet.data)?;
+        version
+            .check_fallback(layout, packet.typ, len, packet.data.first().copied())
+            .map_err(Error::widen)?;
+        input.read_checksum()?;
+        input.end()?;
+
+        Ok(packet)
+    }
+}
+
+impl<'a, const N: usize> PacketRef<'a, N> {
+    /// Parse a packet at the start of `buf`, using the default wire format, header version and
+    /// checksum, as written by `encode_into`. Returns the packet, which borrows its payload from
+    /// `buf` instead of copying it, and the number of bytes it took up, so packets received back
+    /// to back into a DMA buffer can be parsed in turn. Returns `Error::BufferTooSmall` if only
+    /// part of a packet is present.
+    ///
+    /// A framed packet can only be parsed in place if none of its bytes had to be escaped, as
+    /// the payload is borrowed as-is. Otherwise `Error::Framing` is returned, and the packet
+    /// has to be decoded with `PacketDecoder::feed_slice` instead.
+    pub fn parse(buf: &'a [u8]) -> Result<(Self, usize), Error> {
+        Self::parse_as(buf, WireFormat::default(), Version::default())
+    }
+
+    /// Parse a packet at the start of `buf`, using the given wire format and header version
+    pub fn parse_as(
+        buf: &'a [u8],
+        format: WireFormat,
+        version: Version,
+    ) -> Result<(Self, usize), Error> {
+        Self::parse_with(buf, format, version, Crc32::default())
+    }
+
+    /// Parse a packet at the start of `buf`, using the given wire format, header version and
+    /// checksum
+    pub fn parse_with<K: Checksum>(
+        buf: &'a [u8],
+        format: WireFormat,
+        version: Version,
+        checksum: K,
+    ) -> Result<(Self, usize), Error> {
+        if format == WireFormat::Legacy {
+            return Self::parse_unframed(buf, version, checksum);
+        }
+        // Skip the opening delimiter, and any empty frames
+        let start = buf.iter().take_while(|b| **b == FRAME_END).count();
+        let frame = &buf[start..];
+        let end = frame.iter().position(|b| *b == FRAME_END);
+        let body = &frame[..end.unwrap_or(frame.len())];
+        if body.contains(&FRAME_ESC) {
+            return Err(Error::Framing);
+        }
+        let result = Self::parse_unframed(body, version, checksum);
+        match (result, end) {
+            // The packet must fill the frame exactly, and is followed by the closing delimiter
+            (Ok((packet, len)), Some(end)) if len == end => Ok((packet, start + end + 1)),
+            (Ok(_), Some(_)) => Err(Error::Framing),
+            (Ok((_, len)), None) => Err(Error::BufferTooSmall(start + len + 1)),
+            (Err(Error::BufferTooSmall(len)), None) => Err(Error::BufferTooSmall(start + len + 1)),
+            (Err(Error::BufferTooSmall(_)), Some(_)) => Err(Error::Framing),
+            (Err(e), _) => Err(e),
+        }
+    }
+
+    /// Parse a packet in the legacy wire format
+    fn parse_unframed<K: Checksum>(
+        buf: &'a [u8],
+        version: Version,
+        mut checksum: K,
+    ) -> Result<(Self, usize), Error> {
+        let first = *buf
+            .first()
+            .ok_or(Error::BufferTooSmall(version.header_len()))?;
+        let layout = version.detect(first)?;
+        let header_len = layout.header_len();
+        let header = buf
+            .get(..header_len)
+            .ok_or(Error::BufferTooSmall(header_len))?;
+        let (packet, len) = Packet::<Raw<N>, N>::from_header(header, layout)?;
+        version.check_fallback(layout, packet.typ, len, buf.get(header_len).copied())?;
+        let end = header_len + len;
+        let total = end + crc::len::<K>();
+        let received = buf.get(end..total).ok_or(Error::BufferTooSmall(total))?;
+
+        checksum.reset();
+        checksum.update(&buf[..end]);
+        let expected = crc::truncate::<K>(checksum.value());
+        let received = crc::from_bytes::<K>(received);
+        if received != expected {
+            return Err(Error::CrcMismatch { expected, received });
+        }
+        Ok((packet.with_data(&buf[header_len..end]), total))
+    }
+}
+
+impl<D: AsRef<[u8]>, const N: usize> Packet<D, N> {
+    /// Write the packet to the start of `buf`, using the default wire format and header
+    /// version, as read by `read_raw` or a default `PacketDecoder`. Returns the number of bytes
+    /// written, e.g. for a DMA transfer, or `Error::BufferTooSmall` with the number needed.
+    pub fn encode_into(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        self.encode_into_as(buf, WireFormat::default(), Version::default())
+    }
+
+    /// Write the packet to the start of `buf`, using the given wire format and header version.
+    ///
+    /// The packet can be read back in place with `PacketRef::parse_as`, so long as none of its
+    /// bytes had to be escaped for the framed format.
+    pub fn encode_into_as(
+        &self,
+        buf: &mut [u8],
+        format: WireFormat,
+        version: Version,
+    ) -> Result<usize, Error> {
+        self.encode_into_with(buf, format, version, Crc32::default())
+    }
+
+    /// Write the packet to the start of `buf`, using the given wire format, header version and
+    /// checksum
+    pub fn encode_into_with<K: Checksum>(
+        &self,
+        buf: &mut [u8],
+        format: WireFormat,
+        version: Version,
+        checksum: K,
+    ) -> Result<usize, Error> {
+        let header = self.header(version)?;
+        let mut buf = SliceOutput { buf, len: 0 };
+        let mut out = DigesterOutput::new(&mut buf, format, checksum);
+        out.begin()?;
+        out.write_data(&header)?;
+        out.write_data(self.data.as_ref())?;
+        out.write_checksum()?;
+        out.end()?;
+
+        if buf.len > buf.buf.len() {
+            return Err(Error::BufferTooSmall(buf.len));
+        }
+        Ok(buf.len)
+    }
+}
+
+/// Writes bytes to the start of a buffer, counting any which don't fit
+struct SliceOutput<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl SerialWrite for &mut SliceOutput<'_> {
+    type Error = Infallible;
+
+    fn write_byte(&mut self, b: u8) -> nb::Result<(), Infallible> {
+        if let Some(slot) = self.buf.get_mut(self.len) {
+            *slot = b;
+        }
+        self.len += 1;
+        Ok(())
+    }
+}
+
+/// Decodes packets incrementally as bytes arrive, without blocking. Bytes can be fed in from a
+/// UART receive interrupt or a polling loop, and a complete packet is returned once the last
+/// byte of it has been fed in.
+///
+/// `N` is the largest payload accepted, as for `Packet`, and `K` is the checksum used on the
+/// link. `new` and `with_format` create decoders of the default capacity, and their `_sized`
+/// counterparts decoders of any capacity.
+pub struct PacketDecoder<const N: usize = PACKET_LEN, K = Crc32> {
+    format: WireFormat,
+    version: Version,
+    /// Header layout of the packet being received, which is `version` except for a
+    /// `Command::Hello` sent with `Version::V1` headers
+    layout: Version,
+    state: DecodeState,
+    escaped: bool,
+    header: [u8; MAX_HEADER_LEN],
+    data: Raw<N>,
+    received: [u8; MAX_CHECKSUM_LEN],
+    checksum: K,
+}
+
+#[derive(Clone, Copy)]
+enum DecodeState {
+    /// Discarding input until the next frame delimiter
+    Sync,
+    /// Expecting the given header byte
+    Header(usize),
+    /// Reading a payload of the given length
+    Data(usize),
+    /// Expecting the given checksum byte
+    Checksum(usize),
+    /// Expecting the end of the frame
+    End,
+}
+
+impl PacketDecoder {
+    /// Create a decoder for the default wire format and capacity
+    pub fn new() -> Self {
+        Self::new_sized()
+    }
+
+    /// Create a decoder for the given wire format, and the default capacity
+    pub fn with_format(format: WireFormat) -> Self {
+        Self::with_format_sized(format)
+    }
+}
+
+impl<const N: usize> PacketDecoder<N> {
+    /// As `PacketDecoder::new`, for packets of any capacity
+    pub fn new_sized() -> Self {
+        Self::with_format_sized(WireFormat::default())
+    }
+
+    /// As `PacketDecoder::with_format`, for packets of any capacity
+    pub fn with_format_sized(format: WireFormat) -> Self {
+        Self {
+            format,
+            version: Version::default(),
+            layout: Version::default(),
+            state: DecodeState::initial(format),
+            escaped: false,
+            header: Default::default(),
+            data: h::Vec::new(),
+            received: Default::default(),
+            checksum: Crc32::default(),
+        }
+    }
+}
+
+impl<const N: usize, K: Checksum> PacketDecoder<N, K> {
+    /// Use the given checksum, instead of the default CRC-32
+    pub fn with_checksum<L: Checksum>(self, mut checksum: L) -> PacketDecoder<N, L> {
+        checksum.reset();
+        PacketDecoder {
+            format: self.format,
+            version: self.version,
+            layout: self.layout,
+            state: DecodeState::initial(self.format),
+            escaped: false,
+            header: self.header,
+            data: h::Vec::new(),
+            received: self.received,
+            checksum,
+        }
+    }
+
+    /// Use the given header version, e.g. one agreed with `Command::Hello`
+    pub fn with_version(mut self, version: Version) -> Self {
+        self.version = version;
+        self.layout = version;
+        self
+    }
+
+    /// Feed in a single byte. Returns `WouldBlock` until a complete packet has been received.
+    ///
+    /// After an error the rest of the frame is discarded, and decoding continues with the next
+    /// frame.
+    pub fn feed(&mut self, b: u8) -> nb::Result<Packet<Raw<N>, N>, Error> {
+        match self.format {
+            WireFormat::Framed => self.feed_framed(b),
+            WireFormat::Legacy => self.accept(b),
+        }
+    }
+
+    /// Feed in bytes from a buffer, stopping early if a packet is completed or an error occurs.
+    /// Returns the number of bytes consumed along with the result of the last byte fed in.
+    pub fn feed_slice(&mut self, bytes: &[u8]) -> (usize, nb::Result<Packet<Raw<N>, N>, Error>) {
+        for (i, b) in bytes.iter().enumerate() {
+            match self.feed(*b) {
+                Err(nb::Error::WouldBlock) => continue,
+                result => return (i + 1, result),
+            }
+        }
+        (bytes.len(), Err(nb::Error::WouldBlock))
+    }
+
+    /// Discard any partially received packet
+    pub fn reset(&mut self) {
+        self.restart();
+        self.state = DecodeState::initial(self.format);
+    }
+
+    fn feed_framed(&mut self, b: u8) -> nb::Result<Packet<Raw<N>, N>, Error> {
+        if b == FRAME_END {
+            let result = match self.state {
+                DecodeState::Sync | DecodeState::Header(0) => Err(nb::Error::WouldBlock),
+                DecodeState::End => self.finish(),
+                _ => Err(nb::Error::Other(Error::Framing)),
+            };
+            self.restart();
+            return result;
+        }
+
+        let d = match (self.state, self.escaped, b) {
+            (DecodeState::Sync, _, _) => return Err(nb::Error::WouldBlock),
+            (DecodeState::End, _, _) => return self.fail(Error::Framing),
+            (_, false, FRAME_ESC) => {
+                self.escaped = true;
+                return Err(nb::Error::WouldBlock);
+            }
+            (_, false, d) => d,
+            (_, true, FRAME_ESC_END) => FRAME_END,
+            (_, true, FRAME_ESC_ESC) => FRAME_ESC,
+            (_, true, _) => return self.fail(Error::Framing),
+        };
+        self.escaped = false;
+        self.accept(d)
+    }
+
+    /// Accept an unescaped byte of the packet
+    fn accept(&mut self, d: u8) -> nb::Result<Packet<Raw<N>, N>, Error> {
+        match self.state {
+            DecodeState::Header(n) => {
+                if n == 0 {
+                    match self.version.detect(d) {
+                        Ok(layout) => self.layout = layout,
+                        Err(e) => return self.fail(e),
+                    }
+                }
+                self.header[n] = d;
+                self.checksum.update(&[d]);
+                self.state = if n + 1 < self.layout.header_len() {
+                    DecodeState::Header(n + 1)
+                } else {
+                    match self.parse_header(n + 1) {
+                        Ok(0) => DecodeState::Checksum(0),
+                        Ok(len) => DecodeState::Data(len),
+                        Err(e) => return self.fail(e),
+                    }
+                };
+            }
+            DecodeState::Data(len) => {
+                if self.data.push(d).is_err() {
+                    return self.fail(Error::PayloadTooLong(len));
+                }
+                // The packet type was checked with the header, which leaves the opcode
+                let typ = PacketType::Command;
+                if self.data.len() == 1 {
+                    if let Err(e) = self.version.check_fallback(self.layout, typ, len, Some(d)) {
+                        return self.fail(e);
+                    }
+                }
+                self.checksum.update(&[d]);
+                if self.data.len() == len {
+                    self.state = DecodeState::Checksum(0);
+                }
+            }
+            DecodeState::Checksum(n) => {
+                self.received[n] = d;
+                if n + 1 < crc::len::<K>() {
+                    self.state = DecodeState::Checksum(n + 1);
+                } else {
+                    let expected = crc::truncate::<K>(self.checksum.value());
+                    let received = crc::from_bytes::<K>(&self.received);
+                    if received != expected {
+                        return self.fail(Error::CrcMismatch { expected, received });
+                    } else if self.format == WireFormat::Framed {
+                        self.state = DecodeState::End;
+                    } else {
+                        let result = self.finish();
+                        self.restart();
+                        return result;
+                    }
+                }
+            }
+            DecodeState::Sync | DecodeState::End => {}
+        }
+        Err(nb::Error::WouldBlock)
+    }
+
+    /// Check the first `len` bytes of the header, once they have all arrived, and return the
+    /// length of the payload
+    fn parse_header(&self, len: usize) -> Result<usize, Error> {
+        let header = &self.header[..len];
+        let (packet, len) = Packet::<Raw<N>, N>::from_header(header, self.layout)?;
+        self.version
+            .check_fallback(self.layout, packet.typ, len, None)?;
+        Ok(len)
+    }
+
+    /// Take the completed packet
+    fn finish(&mut self) -> nb::Result<Packet<Raw<N>, N>, Error> {
+        let header = &self.header[..self.layout.header_len()];
+        let (mut packet, _) = Packet::from_header(header, self.layout)?;
+        packet.data = core::mem::take(&mut self.data);
+        Ok(packet)
+    }
+
+    /// Prepare to receive the next packet
+    fn restart(&mut self) {
+        self.state = DecodeState::Header(0);
+        self.escaped = false;
+        self.data.clear();
+        self.checksum.reset();
+    }
+
+    /// Abandon the current packet, returning the given error
+    fn fail(&mut self, e: Error) -> nb::Result<Packet<Raw<N>, N>, Error> {
+        self.restart();
+        if self.format == WireFormat::Framed {
+            self.state = DecodeState::Sync;
+        }
+        Err(nb::Error::Other(e))
+    }
+}
+
+impl DecodeState {
+    fn initial(format: WireFormat) -> Self {
+        match format {
+            WireFormat::Framed => DecodeState::Sync,
+            WireFormat::Legacy => DecodeState::Header(0),
+        }
+    }
+}
+
+impl<const N: usize> Default for PacketDecoder<N> {
+    fn default() -> Self {
+        Self::new_sized()
+    }
+}
+
+/// Sends packets without blocking. Each packet is queued with `encode`, and then drained into
+/// the serial device by calling `poll` from a TX-empty interrupt or a cooperative loop until it
+/// completes. Escaping is done as the bytes are written, so no frame buffer is needed.
+///
+/// `N` and `K` are the payload capacity and checksum, as for `PacketDecoder`, and the `_sized`
+/// constructors likewise create encoders of any capacity.
+pub struct PacketEncoder<const N: usize = PACKET_LEN, K = Crc32> {
+    format: WireFormat,
+    version: Version,
+    header: h::Vec<u8, MAX_HEADER_LEN>,
+    data: Raw<N>,
+    trailer: h::Vec<u8, MAX_CHECKSUM_LEN>,
+    checksum: K,
+    state: EncodeState,
+}
+
+#[derive(Clone, Copy)]
+enum EncodeState {
+    /// Nothing left to send
+    Idle,
+    /// Sending the leading frame delimiter
+    Begin,
+    /// Sending the given byte of the header, payload and checksum, or the second byte of its
+    /// escape sequence
+    Body { pos: usize, escaped: bool },
+    /// Sending the trailing frame delimiter
+    End,
+}
+
+impl PacketEncoder {
+    /// Create an encoder for the default wire format and capacity
+    pub fn new() -> Self {
+        Self::new_sized()
+    }
+
+    /// Create an encoder for the given wire format, and the default capacity
+    pub fn with_format(format: WireFormat) -> Self {
+        Self::with_format_sized(format)
+    }
+}
+
+impl<const N: usize> PacketEncoder<N> {
+    /// As `PacketEncoder::new`, for packets of any capacity
+    pub fn new_sized() -> Self {
+        Self::with_format_sized(WireFormat::default())
+    }
+
+    /// As `PacketEncoder::with_format`, for packets of any capacity
+    pub fn with_format_sized(format: WireFormat) -> Self {
+        Self {
+            format,
+            version: Version::default(),
+            header: h::Vec::new(),
+            data: h::Vec::new(),
+            trailer: h::Vec::new(),
+            checksum: Crc32::default(),
+            state: EncodeState::Idle,
+        }
+    }
+}
+
+impl<const N: usize, K: Checksum> PacketEncoder<N, K> {
+    /// Use the given checksum, instead of the default CRC-32. Any queued packet is discarded.
+    pub fn with_checksum<L: Checksum>(self, checksum: L) -> PacketEncoder<N, L> {
+        PacketEncoder {
+            format: self.format,
+            version: self.version,
+            header: h::Vec::new(),
+            data: h::Vec::new(),
+            trailer: h::Vec::new(),
+            checksum,
+            state: EncodeState::Idle,
+        }
+    }
+
+    /// Use the given header version, e.g. one agreed with `Command::Hello`
+    pub fn with_version(mut self, version: Version) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Queue a packet for sending. Returns `WouldBlock` if the previous packet has not been
+    /// completely sent yet.
+    pub fn encode(&mut self, packet: &Packet<Raw<N>, N>) -> nb::Result<(), Error> {
+        if !self.is_idle() {
+            return Err(nb::Error::WouldBlock);
+        }
+        self.header = packet.header(self.version)?;
+        self.data = packet.data.clone();
+        self.checksum.reset();
+        self.checksum.update(&self.header);
+        self.checksum.update(&self.data);
+        let digest = crc::truncate::<K>(self.checksum.value());
+        self.trailer.clear();
+        let _ = self
+            .trailer
+            .extend_from_slice(&crc::to_bytes(digest)[..crc::len::<K>()]);
+        self.state = match self.format {
+            WireFormat::Framed => EncodeState::Begin,
+            WireFormat::Legacy => EncodeState::Body {
+                pos: 0,
+                escaped: false,
+            },
+        };
+        Ok(())
+    }
+
+    /// Write as much of the queued packet as the serial device will accept. Returns
+    /// `WouldBlock` until the whole packet, including the checksum, has been written.
+    pub fn poll<W: SerialWrite>(&mut self, s: &mut W) -> nb::Result<(), Error<W::Error>> {
+        while let Some((b, next)) = self.next_byte() {
+            match s.write_byte(b) {
+                Ok(()) => self.state = next,
+                Err(nb::Error::WouldBlock) => return Err(nb::Error::WouldBlock),
+                Err(nb::Error::Other(e)) => return Err(nb::Error::Other(Error::Serial(e))),
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy as much of the queued packet as fits into `buf`, e.g. for a DMA transfer, and
+    /// return the number of bytes copied. Returns 0 once the whole packet has been taken.
+    pub fn fill(&mut self, buf: &mut [u8]) -> usize {
+        let mut n = 0;
+        while let (Some(slot), Some((b, next))) = (buf.get_mut(n), self.next_byte()) {
+            *slot = b;
+            self.state = next;
+            n += 1;
+        }
+        n
+    }
+
+    /// Returns true if there is no packet waiting to be sent
+    pub fn is_idle(&self) -> bool {
+        matches!(self.state, EncodeState::Idle)
+    }
+
+    /// Discard the rest of the queued packet
+    pub fn reset(&mut self) {
+        self.state = EncodeState::Idle;
+    }
+
+    /// Byte of the header, payload and checksum, before escaping
+    fn body_byte(&self, pos: usize) -> Option<u8> {
+        let data_pos = pos.checked_sub(self.header.len());
+        let checksum_pos = data_pos.and_then(|p| p.checked_sub(self.data.len()));
+        match (data_pos, checksum_pos) {
+            (None, _) => self.header.get(pos).copied(),
+            (Some(p), None) => self.data.get(p).copied(),
+            (_, Some(p)) => self.trailer.get(p).copied(),
+        }
+    }
+
+    /// The next byte to write, and the state to move to once it has been written
+    fn next_byte(&self) -> Option<(u8, EncodeState)> {
+        let body = |pos| EncodeState::Body {
+            pos,
+            escaped: false,
+        };
+        let framed = self.format == WireFormat::Framed;
+        let len = self.header.len() + self.data.len() + self.trailer.len();
+        match self.state {
+            EncodeState::Idle => None,
+            EncodeState::Begin => Some((FRAME_END, body(0))),
+            EncodeState::End => Some((FRAME_END, EncodeState::Idle)),
+            EncodeState::Body { pos, escaped } => {
+                let d = self.body_byte(pos)?;
+                let after = match (pos + 1 < len, framed) {
+                    (true, _) => body(pos + 1),
+                    (false, true) => EncodeState::End,
+                    (false, false) => EncodeState::Idle,
+                };
+                let escape = match d {
+                    FRAME_END => FRAME_ESC_END,
+                    FRAME_ESC => FRAME_ESC_ESC,
+                    _ => return Some((d, after)),
+                };
+                match (framed, escaped) {
+                    (false, _) => Some((d, after)),
+                    (true, false) => Some((FRAME_ESC, EncodeState::Body { pos, escaped: true })),
+                    (true, true) => Some((escape, after)),
+                }
+            }
+        }
+    }
+}
+
+impl<const N: usize> Default for PacketEncoder<N> {
+    fn default() -> Self {
+        Self::new_sized()
+    }
+}
+
+/// Writes bytes to a serial device, adding the delimiters and escapes of the wire format
+pub(crate) struct FrameOutput<O> {
+    output: O,
+    format: WireFormat,
+}
+
+impl<O: SerialWrite> FrameOutput<O> {
+    pub(crate) fn new(output: O, format: WireFormat) -> Self {
+        Self { output, format }
+    }
+
+    /// Start a new frame. Does nothing for the legacy format.
+    pub(crate) fn begin(&mut self) -> Result<(), Error<O::Error>> {
+        match self.format {
+            WireFormat::Framed => self.put(FRAME_END),
+            WireFormat::Legacy => Ok(()),
+        }
+    }
+
+    /// Finish the current frame. Does nothing for the legacy format.
+    pub(crate) fn end(&mut self) -> Result<(), Error<O::Error>> {
+        self.begin()
+    }
+
+    /// Write a single byte, escaping it if necessary
+    pub(crate) fn write(&mut self, d: u8) -> Result<(), Error<O::Error>> {
+        let escaped = match (self.format, d) {
+            (WireFormat::Framed, FRAME_END) => FRAME_ESC_END,
+            (WireFormat::Framed, FRAME_ESC) => FRAME_ESC_ESC,
+            _ => return self.put(d),
+        };
+        self.put(FRAME_ESC)?;
+        self.put(escaped)
+    }
+
+    /// Write a single byte to the device as-is, blocking until it is accepted
+    fn put(&mut self, d: u8) -> Result<(), Error<O::Error>> {
+        nb::block!(self.output.write_byte(d)).map_err(Error::Serial)
+    }
+}
+
+/// Reads bytes from a serial device, removing the delimiters and escapes of the wire format
+pub(crate) struct FrameInput<I> {
+    input: I,
+    format: WireFormat,
+    in_frame: bool,
+}
+
+impl<I: SerialRead> FrameInput<I> {
+    pub(crate) fn new(input: I, format: WireFormat) -> Self {
+        Self {
+            input,
+            format,
+            in_frame: false,
+        }
+    }
+
+    /// Discard input up to and including the next frame delimiter. Does nothing for the
+    /// legacy format.
+    pub(crate) fn sync(&mut self) -> Result<(), Error<I::Error>> {
+        if self.format == WireFormat::Framed {
+            while self.take()? != FRAME_END {}
+        }
+        Ok(())
+    }
+
+    /// Check that the frame ends here. Does nothing for the legacy format.
+    pub(crate) fn end(&mut self) -> Result<(), Error<I::Error>> {
+        match self.format {
+            WireFormat::Framed => match self.take()? {
+                FRAME_END => Ok(()),
+                _ => Err(Error::Framing),
+            },
+            WireFormat::Legacy => Ok(()),
+        }
+    }
+
+    /// Read a single byte, removing any escaping. Empty frames before the start of the packet
+    /// are skipped.
+    pub(crate) fn read(&mut self) -> Result<u8, Error<I::Error>> {
+        if self.format == WireFormat::Legacy {
+            return self.take();
+        }
+        loop {
+            let d = match self.take()? {
+                FRAME_END if !self.in_frame => continue,
+                FRAME_END => return Err(Error::Framing),
+                FRAME_ESC => match self.take()? {
+                    FRAME_ESC_END => FRAME_END,
+                    FRAME_ESC_ESC => FRAME_ESC,
+                    _ => return Err(Error::Framing),
+                },
+                d => d,
+            };
+            self.in_frame = true;
+            return Ok(d);
+        }
+    }
+
+    /// Read a single byte from the device as-is, blocking until one is available
+    fn take(&mut self) -> Result<u8, Error<I::Error>> {
+        nb::block!(self.input.read_byte()).map_err(|e| {
+            if I::timed_out(&e) {
+                Error::Timeout
+            } else {
+                Error::Serial(e)
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crc::Crc8;
+    use crate::sim::MockSerial;
+    use crate::Addr;
+
+    /// A packet whose payload includes both bytes which need escaping
+    fn packet() -> Packet<Raw> {
+        let data = Raw::from_slice(&[1, FRAME_END, 2, FRAME_ESC, 3]).unwrap();
+        let mut packet = Packet::new(PacketType::Command, Addr(5), data).with_source(Addr(2));
+        packet.seq = 7;
+        packet
+    }
+
+    /// A device which isn't ready for every other byte
+    #[derive(Default)]
+    struct Stalling {
+        written: std::vec::Vec<u8>,
+        ready: bool,
+    }
+
+    impl SerialWrite for Stalling {
+        type Error = Infallible;
+
+        fn write_byte(&mut self, b: u8) -> nb::Result<(), Infallible> {
+            self.ready = !self.ready;
+            if !self.ready {
+                return Err(nb::Error::WouldBlock);
+            }
+            self.written.push(b);
+            Ok(())
+        }
+    }
+
+    fn assert_same<D: AsRef<[u8]>>(a: &Packet<D>, b: &Packet<Raw>) {
+        assert_eq!(a.packet_type(), b.packet_type());
+        assert_eq!(a.flags(), b.flags());
+        assert_eq!(a.target(), b.target());
+        assert_eq!(a.source(), b.source());
+        assert_eq!(a.seq(), b.seq());
+        assert_eq!(a.data().as_ref(), &b.data()[..]);
+    }
+
+    fn written(packet: &Packet<Raw>, format: WireFormat) -> std::vec::Vec<u8> {
+        let serial = MockSerial::new();
+        packet
+            .write_raw_as(&serial, format, Version::default())
+            .unwrap();
+        serial.take()
+    }
+
+    #[test]
+    fn encode_round_trip() {
+        let packet = packet();
+        let mut buf = [0; 64];
+
+        // The default is the framed format, as read by a default decoder
+        let len = packet.encode_into(&mut buf).unwrap();
+        assert_eq!(&buf[..len], &written(&packet, WireFormat::Framed)[..]);
+        let mut decoder = PacketDecoder::new();
+        let (n, decoded) = decoder.feed_slice(&buf[..len]);
+        assert_eq!(n, len);
+        assert_same(&decoded.unwrap(), &packet);
+        let serial = MockSerial::new();
+        serial.push(&buf[..len]);
+        assert_same(&Packet::read_raw(&serial).unwrap(), &packet);
+
+        let len = packet
+            .encode_into_as(&mut buf, WireFormat::Legacy, Version::default())
+            .unwrap();
+        assert_eq!(&buf[..len], &written(&packet, WireFormat::Legacy)[..]);
+        let mut decoder = PacketDecoder::with_format(WireFormat::Legacy);
+        let (n, decoded) = decoder.feed_slice(&buf[..len]);
+        assert_eq!(n, len);
+        assert_same(&decoded.unwrap(), &packet);
+    }
+
+    #[test]
+    fn default_capacity_inferred() {
+        let bytes = written(&packet(), WireFormat::Framed);
+        let mut decoder = PacketDecoder::new();
+        let mut decoded = None;
+        for &b in &bytes {
+            if let Ok(p) = decoder.feed(b) {
+                decoded = Some(p);
+            }
+        }
+        assert_eq!(decoded.unwrap().data().capacity(), PACKET_LEN);
+
+        let serial = MockSerial::new();
+        serial.push(&bytes);
+        assert_eq!(
+            Packet::read_raw(&serial).unwrap().data().capacity(),
+            PACKET_LEN
+        );
+
+        let encoder = PacketEncoder::new();
+        assert!(encoder.is_idle());
+        let mut sized = PacketDecoder::<64>::new_sized();
+        assert!(sized.feed_slice(&bytes).1.is_ok());
+    }
+
+    #[test]
+    fn encode_buffer_too_small() {
+        let packet = packet();
+        let len = written(&packet, WireFormat::Framed).len();
+        let mut buf = [0; 64];
+        assert_eq!(
+            packet.encode_into(&mut buf[..len - 1]),
+            Err(Error::BufferTooSmall(len))
+        );
+        assert_eq!(packet.encode_into(&mut buf[..len]), Ok(len));
+    }
+
+    #[test]
+    fn parse_round_trip() {
+        let first = packet();
+        let second = Packet::new(PacketType::Ack, Addr(9), Raw::new()).with_source(Addr(5));
+        let mut buf = [0; 64];
+        let version = Version::default();
+        let a = first
+            .encode_into_with(&mut buf, WireFormat::Legacy, version, Crc8::default())
+            .unwrap();
+        let b = second
+            .encode_into_with(&mut buf[a..], WireFormat::Legacy, version, Crc8::default())
+            .unwrap();
+
+        // Packets back to back in a buffer are parsed in turn, borrowing their payloads
+        let (parsed, len) =
+            PacketRef::parse_with(&buf, WireFormat::Legacy, version, Crc8::default()).unwrap();
+        assert_eq!(len, a);
+        assert_same(&parsed, &first);
+        let (parsed, len) =
+            PacketRef::parse_with(&buf[a..], WireFormat::Legacy, version, Crc8::default()).unwrap();
+        assert_eq!(len, b);
+        assert_same(&parsed, &second);
+
+        // A partial packet asks for the rest, and a corrupted one is rejected
+        assert_eq!(
+            PacketRef::<PACKET_LEN>::parse_with(
+                &buf[..a - 1],
+                WireFormat::Legacy,
+                version,
+                Crc8::default()
+            )
+            .unwrap_err(),
+            Error::BufferTooSmall(a)
+        );
+        buf[3] ^= 1;
+        assert!(matches!(
+            PacketRef::<PACKET_LEN>::parse_with(&buf, WireFormat::Legacy, version, Crc8::default()),
+            Err(Error::CrcMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn parse_framed_in_place() {
+        // With the defaults, what encode_into writes, parse reads
+        let plain = Packet::new(
+            PacketType::Command,
+            Addr(5),
+            Raw::from_slice(&[1, 2, 3]).unwrap(),
+        );
+        let mut buf = [0; 64];
+        let a = plain.encode_into(&mut buf).unwrap();
+        let (parsed, len) = PacketRef::parse(&buf[..a]).unwrap();
+        assert_eq!(len, a);
+        assert_same(&parsed, &plain);
+
+        // Back to back frames, and a partial one
+        let b = plain.encode_into(&mut buf[a..]).unwrap();
+        let (parsed, len) = PacketRef::parse(&buf[a..a + b]).unwrap();
+        assert_eq!(len, b);
+        assert_same(&parsed, &plain);
+        assert_eq!(
+            PacketRef::<PACKET_LEN>::parse(&buf[..a - 1]).unwrap_err(),
+            Error::BufferTooSmall(a)
+        );
+
+        // Escaped bytes can't be borrowed as-is
+        let len = packet().encode_into(&mut buf).unwrap();
+        assert_eq!(
+            PacketRef::<PACKET_LEN>::parse(&buf[..len]).unwrap_err(),
+            Error::Framing
+        );
+    }
+
+    #[test]
+    fn decode_after_garbage() {
+        let packet = packet();
+        let mut bytes = std::vec![0x55, FRAME_ESC, 0xAA];
+        bytes.extend(written(&packet, WireFormat::Framed));
+        let mut decoder = PacketDecoder::new();
+        let (n, decoded) = decoder.feed_slice(&bytes);
+        assert_eq!(n, bytes.len());
+        assert_same(&decoded.unwrap(), &packet);
+    }
+
+    #[test]
+    fn decode_bad_escape() {
+        let packet = packet();
+        let frame = written(&packet, WireFormat::Framed);
+        let mut bytes = frame.clone();
+        let esc = bytes.iter().position(|b| *b == FRAME_ESC).unwrap();
+        bytes[esc + 1] = 0x01;
+        bytes.extend(&frame);
+
+        // The rest of the broken frame is skipped, and the next one decoded
+        let mut decoder = PacketDecoder::new();
+        let (n, result) = decoder.feed_slice(&bytes);
+        assert_eq!(n, esc + 2);
+        assert!(matches!(result, Err(nb::Error::Other(Error::Framing))));
+        let (n, decoded) = decoder.feed_slice(&bytes[esc + 2..]);
+        assert_eq!(esc + 2 + n, bytes.len());
+        assert_same(&decoded.unwrap(), &packet);
+    }
+
+    #[test]
+    fn decode_after_crc_failure() {
+        let packet = packet();
+        let frame = written(&packet, WireFormat::Framed);
+        let mut bytes = frame.clone();
+        // The last checksum byte, before the closing delimiter
+        let last = bytes.len() - 2;
+        bytes[last] ^= 0x01;
+        bytes.extend(&frame);
+
+        let mut decoder = PacketDecoder::new();
+        let (n, result) = decoder.feed_slice(&bytes);
+        assert_eq!(n, last + 1);
+        assert!(matches!(
+            result,
+            Err(nb::Error::Other(Error::CrcMismatch { .. }))
+        ));
+        let (n, decoded) = decoder.feed_slice(&bytes[last + 1..]);
+        assert_eq!(last + 1 + n, bytes.len());
+        assert_same(&decoded.unwrap(), &packet);
+    }
+
+    #[test]
+    fn feed_slice_stops_after_packet() {
+        let first = packet();
+        let second = Packet::new(PacketType::Ack, Addr(9), Raw::new());
+        let a = written(&first, WireFormat::Legacy);
+        let mut bytes = a.clone();
+        bytes.extend(written(&second, WireFormat::Legacy));
+
+        let mut decoder = PacketDecoder::with_format(WireFormat::Legacy);
+        let (n, decoded) = decoder.feed_slice(&bytes[..3]);
+        assert_eq!(n, 3);
+        assert!(matches!(decoded, Err(nb::Error::WouldBlock)));
+        let (n, decoded) = decoder.feed_slice(&bytes[3..]);
+        assert_eq!(3 + n, a.len());
+        assert_same(&decoded.unwrap(), &first);
+        let (n, decoded) = decoder.feed_slice(&bytes[a.len()..]);
+        assert_eq!(a.len() + n, bytes.len());
+        assert_same(&decoded.unwrap(), &second);
+    }
+
+    #[test]
+    fn encode_through_stalling_device() {
+        let packet = packet();
+        for format in [WireFormat::Framed, WireFormat::Legacy] {
+            let mut encoder = PacketEncoder::with_format(format);
+            encoder.encode(&packet).unwrap();
+            assert!(matches!(
+                encoder.encode(&packet),
+                Err(nb::Error::WouldBlock)
+            ));
+            let mut device = Stalling::default();
+            let mut polls = 0;
+            while let Err(nb::Error::WouldBlock) = encoder.poll(&mut device) {
+                polls += 1;
+            }
+            assert!(encoder.is_idle());
+            // Every byte after the first stalls once
+            assert_eq!(polls, device.written.len() - 1);
+            assert_eq!(device.written, written(&packet, format));
+        }
+    }
+
+    #[test]
+    fn fill_small_buffers() {
+        let packet = packet();
+        for format in [WireFormat::Framed, WireFormat::Legacy] {
+            for size in 1..4 {
+                let mut encoder = PacketEncoder::with_format(format);
+                encoder.encode(&packet).unwrap();
+                let mut bytes = std::vec::Vec::<u8>::new();
+                let mut buf = [0; 3];
+                loop {
+                    let n = encoder.fill(&mut buf[..size]);
+                    if n == 0 {
+                        break;
+                    }
+                    bytes.extend(&buf[..n]);
+                }
+                assert!(encoder.is_idle());
+                assert_eq!(bytes, written(&packet, format));
+            }
+        }
+    }
+}
@@ -0,0 +1,216 @@
+//! The serial device traits used for blocking and non-blocking packet I/O, and adapters for
+//! the serial traits of the embedded-hal crates.
+//!
+//! Devices implementing the embedded-hal 0.2 traits can be used directly, with the
+//! `embedded-hal-02` feature, which is enabled by default. Devices implementing the
+//! embedded-hal-nb or embedded-io traits are wrapped in `HalNb` or `EmbeddedIo`, with the
+//! feature of the same name, and host serial ports, sockets and files in `StdIo`, with the
+//! `std` feature.
+
+/// A serial device which bytes can be written to
+pub trait SerialWrite {
+    type Error;
+
+    /// Write a single byte, returning `WouldBlock` if the device is not ready for it
+    fn write_byte(&mut self, b: u8) -> nb::Result<(), Self::Error>;
+}
+
+/// A serial device which bytes can be read from
+pub trait SerialRead {
+    type Error;
+
+    /// Read a single byte, returning `WouldBlock` if none has been received yet
+    fn read_byte(&mut self) -> nb::Result<u8, Self::Error>;
+
+    /// Returns true if the error means that no byte arrived in time, so that it is reported as
+    /// `Error::Timeout` rather than `Error::Serial`
+    fn timed_out(_error: &Self::Error) -> bool {
+        false
+    }
+}
+
+#[cfg(feature = "embedded-hal-02")]
+impl<S: embedded_hal::serial::Write<u8>> SerialWrite for S {
+    type Error = S::Error;
+
+    fn write_byte(&mut self, b: u8) -> nb::Result<(), S::Error> {
+        self.write(b)
+    }
+}
+
+#[cfg(feature = "embedded-hal-02")]
+impl<S: embedded_hal::serial::Read<u8>> SerialRead for S {
+    type Error = S::Error;
+
+    fn read_byte(&mut self) -> nb::Result<u8, S::Error> {
+        self.read()
+    }
+}
+
+/// A serial device implementing the embedded-hal-nb traits, as for embedded-hal 1.0 HALs
+#[cfg(feature = "embedded-hal-nb")]
+#[derive(Debug)]
+pub struct HalNb<T>(pub T);
+
+#[cfg(feature = "embedded-hal-nb")]
+impl<T: embedded_hal_nb::serial::Write<u8>> SerialWrite for HalNb<T> {
+    type Error = T::Error;
+
+    fn write_byte(&mut self, b: u8) -> nb::Result<(), T::Error> {
+        self.0.write(b)
+    }
+}
+
+#[cfg(feature = "embedded-hal-nb")]
+impl<T: embedded_hal_nb::serial::Read<u8>> SerialRead for HalNb<T> {
+    type Error = T::Error;
+
+    fn read_byte(&mut self) -> nb::Result<u8, T::Error> {
+        self.0.read()
+    }
+}
+
+/// A device implementing the blocking embedded-io traits. Every byte blocks until the device
+/// accepts or delivers it, so `WouldBlock` is never returned. The end of the stream is
+/// reported as `ReadExactError::UnexpectedEof`, and reads which fail with
+/// `ErrorKind::TimedOut` give `Error::Timeout`.
+#[cfg(feature = "embedded-io")]
+#[derive(Debug)]
+pub struct EmbeddedIo<T>(pub T);
+
+#[cfg(feature = "embedded-io")]
+impl<T: embedded_io::Write> SerialWrite for EmbeddedIo<T> {
+    type Error = T::Error;
+
+    fn write_byte(&mut self, b: u8) -> nb::Result<(), T::Error> {
+        self.0.write_all(&[b]).map_err(nb::Error::Other)
+    }
+}
+
+#[cfg(feature = "embedded-io")]
+impl<T: embedded_io::Read> SerialRead for EmbeddedIo<T> {
+    type Error = embedded_io::ReadExactError<T::Error>;
+
+    fn read_byte(&mut self) -> nb::Result<u8, Self::Error> {
+        let mut b = [0];
+        self.0.read_exact(&mut b).map_err(nb::Error::Other)?;
+        Ok(b[0])
+    }
+
+    fn timed_out(error: &Self::Error) -> bool {
+        match error {
+            embedded_io::ReadExactError::Other(e) => {
+                embedded_io::Error::kind(e) == embedded_io::ErrorKind::TimedOut
+            }
+            embedded_io::ReadExactError::UnexpectedEof => false,
+        }
+    }
+}
+
+/// A host serial port, socket, PTY or other stream implementing `std::io::Read` and `Write`.
+/// Every byte blocks until the stream accepts or delivers it. If a read timeout is set on the
+/// stream, e.g. with `TcpStream::set_read_timeout`, reads which time out give `Error::Timeout`.
+///
+/// As each byte is read separately, reading through a `std::io::BufReader` saves system calls.
+/// Likewise each byte is written with its own `write_all`, so writing through a
+/// `std::io::BufWriter`, flushed after each packet, saves one system call per byte.
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub struct StdIo<T>(pub T);
+
+#[cfg(feature = "std")]
+impl<T: std::io::Write> SerialWrite for StdIo<T> {
+    type Error = std::io::Error;
+
+    fn write_byte(&mut self, b: u8) -> nb::Result<(), std::io::Error> {
+        self.0.write_all(&[b]).map_err(nb::Error::Other)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: std::io::Read> SerialRead for StdIo<T> {
+    type Error = std::io::Error;
+
+    fn read_byte(&mut self) -> nb::Result<u8, std::io::Error> {
+        let mut b = [0];
+        self.0.read_exact(&mut b).map_err(nb::Error::Other)?;
+        Ok(b[0])
+    }
+
+    fn timed_out(error: &std::io::Error) -> bool {
+        // Unix reports a read timeout as WouldBlock, and Windows as TimedOut
+        matches!(
+            error.kind(),
+            std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+        )
+    }
+}
+
+#[cfg(all(test, any(all(feature = "std", unix), feature = "embedded-io")))]
+mod tests {
+    use super::*;
+    use crate::{Error, Packet, Raw};
+
+    /// Fails every read with the given error
+    #[cfg(feature = "embedded-io")]
+    struct Failing(embedded_io::ErrorKind);
+
+    #[cfg(feature = "embedded-io")]
+    impl embedded_io::ErrorType for Failing {
+        type Error = embedded_io::ErrorKind;
+    }
+
+    #[cfg(feature = "embedded-io")]
+    impl embedded_io::Read for Failing {
+        fn read(&mut self, _buf: &mut [u8]) -> Result<usize, Self::Error> {
+            Err(self.0)
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "embedded-io")]
+    fn embedded_io_timeout() {
+        let read = |kind| Packet::<Raw>::read_raw(EmbeddedIo(Failing(kind))).unwrap_err();
+        assert_eq!(read(embedded_io::ErrorKind::TimedOut), Error::Timeout);
+        assert!(matches!(
+            read(embedded_io::ErrorKind::Other),
+            Error::Serial(embedded_io::ReadExactError::Other(_))
+        ));
+    }
+
+    #[test]
+    #[cfg(all(feature = "std", unix))]
+    fn std_io() {
+        use std::io::{BufWriter, Write};
+        use std::os::unix::net::UnixStream;
+        use std::time::Duration;
+
+        use crate::{Addr, PacketType};
+
+        let (a, b) = UnixStream::pair().unwrap();
+        b.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        assert!(matches!(
+            Packet::<Raw>::read_raw(StdIo(&b)),
+            Err(Error::Timeout)
+        ));
+
+        let packet = Packet::new(
+            PacketType::Command,
+            Addr(3),
+            Raw::from_slice(&[1, 0xC0, 0xDB, 4]).unwrap(),
+        );
+        packet.write_raw(StdIo(&a)).unwrap();
+        let mut writer = BufWriter::new(&a);
+        packet.write_raw(StdIo(&mut writer)).unwrap();
+        writer.flush().unwrap();
+        for _ in 0..2 {
+            let read = Packet::<Raw>::read_raw(StdIo(&b)).unwrap();
+            assert_eq!(read.target(), Addr(3));
+            assert_eq!(read.data(), packet.data());
+        }
+        assert!(matches!(
+            Packet::<Raw>::read_raw(StdIo(&b)),
+            Err(Error::Timeout)
+        ));
+    }
+}
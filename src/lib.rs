@@ -1,229 +1,102 @@
 #![no_std]
 
-use core::{
-    convert::{Infallible, TryFrom},
-    hash::Hasher,
+#[cfg(any(test, feature = "std"))]
+extern crate std;
+
+use core::{convert::Infallible, fmt};
+
+#[cfg(feature = "async")]
+pub mod asynch;
+pub mod command;
+pub mod crc;
+pub mod endpoint;
+pub mod enumeration;
+pub mod fragment;
+pub mod framing;
+pub mod io;
+pub mod midi;
+pub mod packet;
+pub mod reliable;
+#[cfg(any(test, feature = "std"))]
+pub mod sim;
+
+pub use crate::crc::{Checksum, CRC};
+pub use crate::framing::{PacketDecoder, PacketEncoder, WireFormat};
+pub use crate::io::{SerialRead, SerialWrite};
+pub use crate::packet::{
+    Addr, Decode, Encode, Flags, Packet, PacketRef, PacketType, Raw, Version, BROADCAST,
+    CONTROLLER, PACKET_LEN,
 };
 
-use bitflags::bitflags;
-use crc::crc32;
-use embedded_hal::serial::{Read, Write};
-use heapless as h;
-
-pub enum Error {
-    IoError,
-    ParseError,
-}
-
-/// Packet containing data of type `D`. In general, D should implement Encode and Decode
-pub struct Packet<D> {
-    typ: PacketType,
-    flags: Flags,
-    target: Addr,
-    data: D,
-}
-
-bitflags! {
-    struct Flags: u8 {
-        const IGNORE = 0b00000001;
-    }
-}
-
-#[repr(transparent)]
-#[derive(Clone, Copy)]
-pub struct Addr(u8);
-
-pub const CONTROLLER: Addr = Addr(0);
-pub const BROADCAST: Addr = Addr(255);
-
-pub type CRC = u32; // For now.
-
-#[derive(Clone, Copy)]
-#[repr(u8)]
-pub enum PacketType {
-    Command = 0x01,
-    MidiEvent = 0x02,
-
-    Raw = 0xFF, // not really useful on its own
-}
-
-impl TryFrom<u8> for PacketType {
-    type Error = Error;
-
-    fn try_from(value: u8) -> Result<Self, Self::Error> {
-        match value {
-            0x01 => Ok(PacketType::Command),
-            0x02 => Ok(PacketType::MidiEvent),
-            0xFF => Ok(PacketType::Raw),
-            _ => Err(Error::ParseError),
-        }
-    }
-}
-
-/// Each packet contains 32 data bytes.
-const PACKET_LEN: usize = 32;
-
-type Raw = h::Vec<u8, PACKET_LEN>;
-
-pub trait Encode {
-    fn data(&self) -> Raw;
-}
-
-pub trait Decode: Sized {
-    type Error;
-    fn decode(raw: Raw) -> Result<Self, Self::Error>;
-}
-
-impl Encode for Raw {
-    fn data(&self) -> Raw {
-        self.clone()
-    }
-}
-
-impl Decode for Raw {
-    type Error = Infallible;
-    fn decode(raw: Raw) -> Result<Self, Self::Error> {
-        Ok(raw)
-    }
-}
-
-impl<D> Packet<D>
-where
-    D: Encode + Decode,
-{
-    pub fn write(&self, s: impl Write<u8>) -> Result<(), Error> {
-        self.encoded().write_raw(s)
-    }
-
-    pub fn with_data<F>(&self, data: F) -> Packet<F> {
-        Packet {
-            typ: self.typ,
-            flags: self.flags,
-            target: self.target,
-            data,
+/// Errors which can occur while sending or receiving packets. `E` is the error type of the
+/// underlying serial device, if there is one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error<E = Infallible> {
+    /// The serial device reported an error
+    Serial(E),
+    /// The checksum received with a packet did not match the one calculated from its contents
+    CrcMismatch { expected: CRC, received: CRC },
+    /// The packet type byte does not correspond to a known `PacketType`
+    UnknownPacketType(u8),
+    /// The flags byte has reserved bits set, or an invalid combination of flags
+    InvalidFlags(u8),
+    /// The packet was sent with a different header version, given here, to the one expected.
+    /// Headers with no version byte are reported as version 1.
+    IncompatibleVersion(u8),
+    /// The payload length is greater than the capacity of the packet, `PACKET_LEN` by default
+    PayloadTooLong(usize),
+    /// The buffer is too small for the packet, which needs the given number of bytes. When
+    /// parsing, more of the packet has still to be received.
+    BufferTooSmall(usize),
+    /// The packet was not correctly framed, e.g. it was truncated or contained a bad escape
+    Framing,
+    /// The operation did not complete in time
+    Timeout,
+}
+
+impl<E> Error<E> {
+    /// Convert the serial device error, leaving other errors as they are
+    pub fn map_serial<F>(self, f: impl FnOnce(E) -> F) -> Error<F> {
+        match self {
+            Error::Serial(e) => Error::Serial(f(e)),
+            Error::CrcMismatch { expected, received } => Error::CrcMismatch { expected, received },
+            Error::UnknownPacketType(t) => Error::UnknownPacketType(t),
+            Error::InvalidFlags(f) => Error::InvalidFlags(f),
+            Error::IncompatibleVersion(v) => Error::IncompatibleVersion(v),
+            Error::PayloadTooLong(len) => Error::PayloadTooLong(len),
+            Error::BufferTooSmall(len) => Error::BufferTooSmall(len),
+            Error::Framing => Error::Framing,
+            Error::Timeout => Error::Timeout,
         }
     }
-
-    pub fn encoded(&self) -> Packet<Raw> {
-        let d = self.data.data();
-        self.with_data(d)
-    }
-}
-
-impl Packet<Raw> {
-    /// Write out a raw packet to the stream
-    pub fn write_raw(&mut self, s: impl Write<u8>) -> Result<(), Error> {
-        let mut out = DigesterOutput::new(s);
-        out.write(self.typ as u8)?;
-        out.write(self.flags.bits())?;
-        out.write(self.target.0)?;
-        out.write_data(self.data.as_ref())?;
-        out.write_checksum()?;
-
-        Ok(())
-    }
-
-    /// Read in a raw packet
-    pub fn read_raw(s: impl Read<u8>) -> Result<Self, Error> {
-        let mut input = DigesterInput::new(s);
-        let packet_type = PacketType::try_from(input.read()?)?;
-        let flags = Flags::from_bits(input.read()?).ok_or(Error::ParseError)?;
-        let target = Addr(input.read()?);
-        let mut data: [u8; PACKET_LEN] = Default::default();
-        input.read_data(&mut data)?;
-        input.read_checksum()?;
-        let data = h::Vec::from_slice(&data).map_err(|_| Error::ParseError)?;
-
-        Ok(Packet {
-            typ: packet_type,
-            flags,
-            target,
-            data,
-        })
-    }
-}
-
-/// Writes data to a serial device, and calculates the CRC32 checksum as data is written
-struct DigesterOutput<O> {
-    output: O,
-    digest: crc32::Digest,
 }
 
-impl<O: Write<u8>> DigesterOutput<O> {
-    fn new(output: O) -> Self {
-        let digest = crc32::Digest::new(crc32::IEEE);
-        Self { output, digest }
-    }
-
-    /// Write a single byte
-    fn write(&mut self, d: u8) -> Result<(), Error> {
-        self.output.write(d).map_err(to_io_error)?;
-        self.digest.write_u8(d);
-        Ok(())
-    }
-
-    /// Write a number of bytes from a buffer.
-    fn write_data(&mut self, d: &[u8]) -> Result<(), Error> {
-        for b in d {
-            self.write(*b)?;
-        }
-        Ok(())
-    }
-
-    /// Write out the calculated checksum, and return it.
-    fn write_checksum(&mut self) -> Result<CRC, Error> {
-        let digest = self.digest.finish() as u32;
-        for b in &digest.to_le_bytes() {
-            self.output.write(*b).map_err(to_io_error)?;
-        }
-        Ok(digest)
+impl Error {
+    /// Convert an error which can't have come from a serial device into one which could have
+    pub fn widen<E>(self) -> Error<E> {
+        self.map_serial(|e| match e {})
     }
 }
 
-/// Reads data from a serial device, and cumulatively calculates the CRC32 checksum
-struct DigesterInput<I> {
-    input: I,
-    digest: crc32::Digest,
-}
-
-impl<I: Read<u8>> DigesterInput<I> {
-    fn new(input: I) -> Self {
-        let digest = crc32::Digest::new(crc32::IEEE);
-        Self { input, digest }
-    }
-
-    /// Read a single byte
-    fn read(&mut self) -> Result<u8, Error> {
-        let d = self.input.read().map_err(to_io_error)?;
-        self.digest.write_u8(d);
-
-        Ok(d)
-    }
-
-    /// Read `LEN` bytes into a buffer
-    fn read_data<const LEN: usize>(&mut self, buf: &mut [u8; LEN]) -> Result<(), Error> {
-        for i in 0..LEN {
-            buf[i] = self.read()?;
-        }
-        Ok(())
-    }
-
-    /// Read the checksum from the stream, and compare it to the calculated checksum
-    fn read_checksum(&mut self) -> Result<(), Error> {
-        let mut buf: [u8; 4] = Default::default();
-        for i in 0..4 {
-            buf[i] = self.input.read().map_err(to_io_error)?;
-        }
-        let packet_checksum = u32::from_le_bytes(buf);
-        let calc_checksum = self.digest.finish() as u32;
-        if packet_checksum == calc_checksum {
-            Ok(())
-        } else {
-            Err(Error::IoError)
+impl<E: fmt::Debug> fmt::Display for Error<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Serial(e) => write!(f, "serial device error: {:?}", e),
+            Error::CrcMismatch { expected, received } => write!(
+                f,
+                "checksum mismatch: expected {:#010x}, received {:#010x}",
+                expected, received
+            ),
+            Error::UnknownPacketType(t) => write!(f, "unknown packet type {:#04x}", t),
+            Error::InvalidFlags(flags) => write!(f, "invalid flags {:#010b}", flags),
+            Error::IncompatibleVersion(v) => write!(f, "incompatible protocol version {}", v),
+            Error::PayloadTooLong(len) => write!(f, "payload of {} bytes is too long", len),
+            Error::BufferTooSmall(len) => write!(f, "buffer too small, {} bytes needed", len),
+            Error::Framing => f.write_str("framing error"),
+            Error::Timeout => f.write_str("timed out"),
         }
     }
 }
 
-fn to_io_error<E>(_err: nb::Error<E>) -> Error {
-    Error::IoError // TODO: return context info along with error
-}
+#[cfg(feature = "std")]
+impl<E: fmt::Debug> std::error::Error for Error<E> {}
@@ -0,0 +1,527 @@
+//! Payload for `PacketType::MidiEvent` packets.
+//!
+//! Events are encoded as the equivalent MIDI message bytes, so a module can forward them to
+//! or from a MIDI port without any translation. SysEx messages are split into fragments which
+//! each fit in a single packet.
+
+use core::fmt;
+
+use heapless as h;
+
+use crate::packet::Capacity;
+use crate::{Decode, Encode, Raw, PACKET_LEN};
+
+/// Maximum number of SysEx data bytes carried by a single fragment
+pub const SYSEX_FRAGMENT_LEN: usize = PACKET_LEN - 2;
+
+const NOTE_OFF: u8 = 0x80;
+const NOTE_ON: u8 = 0x90;
+const POLY_AFTERTOUCH: u8 = 0xA0;
+const CONTROL_CHANGE: u8 = 0xB0;
+const PROGRAM_CHANGE: u8 = 0xC0;
+const CHANNEL_AFTERTOUCH: u8 = 0xD0;
+const PITCH_BEND: u8 = 0xE0;
+const SYSEX: u8 = 0xF0;
+const CLOCK: u8 = 0xF8;
+const START: u8 = 0xFA;
+const CONTINUE: u8 = 0xFB;
+const STOP: u8 = 0xFC;
+
+/// A MIDI channel, from 0 to 15
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Channel(u8);
+
+impl Channel {
+    pub fn new(channel: u8) -> Option<Self> {
+        if channel < 16 {
+            Some(Channel(channel))
+        } else {
+            None
+        }
+    }
+
+    pub fn get(self) -> u8 {
+        self.0
+    }
+}
+
+/// A 7-bit MIDI data value, from 0 to 127
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct U7(u8);
+
+impl U7 {
+    pub fn new(value: u8) -> Option<Self> {
+        if value < 0x80 {
+            Some(U7(value))
+        } else {
+            None
+        }
+    }
+
+    pub fn get(self) -> u8 {
+        self.0
+    }
+}
+
+/// A 14-bit MIDI data value, from 0 to 16383
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct U14(u16);
+
+impl U14 {
+    /// The centre position of the pitch bend wheel
+    pub const CENTRE: U14 = U14(0x2000);
+
+    pub fn new(value: u16) -> Option<Self> {
+        if value < 0x4000 {
+            Some(U14(value))
+        } else {
+            None
+        }
+    }
+
+    pub fn get(self) -> u16 {
+        self.0
+    }
+
+    fn lsb(self) -> u8 {
+        (self.0 & 0x7F) as u8
+    }
+
+    fn msb(self) -> u8 {
+        (self.0 >> 7) as u8
+    }
+}
+
+/// Where a SysEx fragment falls within the complete message
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum SysExFragment {
+    /// The whole message fits in this fragment
+    Complete = 0x00,
+    /// The first fragment of a longer message
+    Start = 0x01,
+    /// A fragment from the middle of a message
+    Continue = 0x02,
+    /// The last fragment of a message
+    End = 0x03,
+}
+
+/// Part of a SysEx message, excluding the leading `0xF0` and trailing `0xF7` bytes
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SysEx {
+    fragment: SysExFragment,
+    data: h::Vec<u8, SYSEX_FRAGMENT_LEN>,
+}
+
+impl SysEx {
+    /// Create a fragment. Returns `None` if there are more than `SYSEX_FRAGMENT_LEN` bytes, or
+    /// any byte is not a 7-bit value.
+    pub fn new(fragment: SysExFragment, data: &[u8]) -> Option<Self> {
+        if data.iter().any(|b| b & 0x80 != 0) {
+            return None;
+        }
+        let data = h::Vec::from_slice(data).ok()?;
+        Some(SysEx { fragment, data })
+    }
+
+    /// Split a complete SysEx message into fragments. Returns `None` if any byte is not a
+    /// 7-bit value.
+    pub fn fragments(message: &[u8]) -> Option<Fragments<'_>> {
+        if message.iter().any(|b| b & 0x80 != 0) {
+            return None;
+        }
+        Some(Fragments {
+            remaining: message,
+            started: false,
+        })
+    }
+
+    pub fn fragment(&self) -> SysExFragment {
+        self.fragment
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+/// Iterator over the fragments of a SysEx message, returned by `SysEx::fragments`
+pub struct Fragments<'a> {
+    remaining: &'a [u8],
+    started: bool,
+}
+
+impl Iterator for Fragments<'_> {
+    type Item = SysEx;
+
+    fn next(&mut self) -> Option<SysEx> {
+        if self.started && self.remaining.is_empty() {
+            return None;
+        }
+        let len = self.remaining.len().min(SYSEX_FRAGMENT_LEN);
+        let (data, rest) = self.remaining.split_at(len);
+        let fragment = match (self.started, rest.is_empty()) {
+            (false, true) => SysExFragment::Complete,
+            (false, false) => SysExFragment::Start,
+            (true, false) => SysExFragment::Continue,
+            (true, true) => SysExFragment::End,
+        };
+        self.remaining = rest;
+        self.started = true;
+        SysEx::new(fragment, data)
+    }
+}
+
+/// A MIDI event
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum MidiEvent {
+    NoteOff {
+        channel: Channel,
+        note: U7,
+        velocity: U7,
+    },
+    NoteOn {
+        channel: Channel,
+        note: U7,
+        velocity: U7,
+    },
+    /// Polyphonic key pressure
+    PolyAftertouch {
+        channel: Channel,
+        note: U7,
+        pressure: U7,
+    },
+    ControlChange {
+        channel: Channel,
+        controller: U7,
+        value: U7,
+    },
+    ProgramChange {
+        channel: Channel,
+        program: U7,
+    },
+    /// Channel pressure
+    ChannelAftertouch {
+        channel: Channel,
+        pressure: U7,
+    },
+    PitchBend {
+        channel: Channel,
+        value: U14,
+    },
+    SysEx(SysEx),
+    Clock,
+    Start,
+    Continue,
+    Stop,
+}
+
+/// Reasons a `MidiEvent` payload can fail to decode
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DecodeError {
+    /// The status byte is not a supported MIDI message
+    UnknownStatus(u8),
+    /// The payload length is wrong for the message
+    Length(usize),
+    /// A data byte is not a 7-bit value
+    OutOfRange(u8),
+    /// The SysEx fragment byte is not a known `SysExFragment`
+    UnknownFragment(u8),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnknownStatus(s) => write!(f, "unknown MIDI status byte {:#04x}", s),
+            DecodeError::Length(len) => write!(f, "wrong MIDI payload length {}", len),
+            DecodeError::OutOfRange(b) => write!(f, "MIDI data byte {:#04x} out of range", b),
+            DecodeError::UnknownFragment(b) => write!(f, "unknown SysEx fragment {:#04x}", b),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for DecodeError {}
+
+impl<const N: usize> Encode<N> for MidiEvent {
+    fn data(&self) -> Raw<N> {
+        let () = Capacity::<N>::FITS_PAYLOADS;
+        let mut raw = Raw::new();
+        // Every message fits in PACKET_LEN, which N is at least, so none of these can fail
+        let _ = match self {
+            MidiEvent::NoteOff {
+                channel,
+                note,
+                velocity,
+            } => raw.extend_from_slice(&[NOTE_OFF | channel.0, note.0, velocity.0]),
+            MidiEvent::NoteOn {
+                channel,
+                note,
+                velocity,
+            } => raw.extend_from_slice(&[NOTE_ON | channel.0, note.0, velocity.0]),
+            MidiEvent::PolyAftertouch {
+                channel,
+                note,
+                pressure,
+            } => raw.extend_from_slice(&[POLY_AFTERTOUCH | channel.0, note.0, pressure.0]),
+            MidiEvent::ControlChange {
+                channel,
+                controller,
+                value,
+            } => raw.extend_from_slice(&[CONTROL_CHANGE | channel.0, controller.0, value.0]),
+            MidiEvent::ProgramChange { channel, program } => {
+                raw.extend_from_slice(&[PROGRAM_CHANGE | channel.0, program.0])
+            }
+            MidiEvent::ChannelAftertouch { channel, pressure } => {
+                raw.extend_from_slice(&[CHANNEL_AFTERTOUCH | channel.0, pressure.0])
+            }
+            MidiEvent::PitchBend { channel, value } => {
+                raw.extend_from_slice(&[PITCH_BEND | channel.0, value.lsb(), value.msb()])
+            }
+            MidiEvent::SysEx(sysex) => raw
+                .extend_from_slice(&[SYSEX, sysex.fragment as u8])
+                .and_then(|_| raw.extend_from_slice(&sysex.data)),
+            MidiEvent::Clock => raw.push(CLOCK).map_err(|_| ()),
+            MidiEvent::Start => raw.push(START).map_err(|_| ()),
+            MidiEvent::Continue => raw.push(CONTINUE).map_err(|_| ()),
+            MidiEvent::Stop => raw.push(STOP).map_err(|_| ()),
+        };
+        raw
+    }
+}
+
+impl<const N: usize> Decode<N> for MidiEvent {
+    type Error = DecodeError;
+
+    fn decode(raw: Raw<N>) -> Result<Self, Self::Error> {
+        let (&status, data) = raw.split_first().ok_or(DecodeError::Length(0))?;
+        if status == SYSEX {
+            let (&fragment, data) = data.split_first().ok_or(DecodeError::Length(raw.len()))?;
+            let fragment = match fragment {
+                0x00 => SysExFragment::Complete,
+                0x01 => SysExFragment::Start,
+                0x02 => SysExFragment::Continue,
+                0x03 => SysExFragment::End,
+                _ => return Err(DecodeError::UnknownFragment(fragment)),
+            };
+            return Ok(MidiEvent::SysEx(SysEx {
+                fragment,
+                data: h::Vec::from_slice(u7_slice(data)?)
+                    .map_err(|_| DecodeError::Length(raw.len()))?,
+            }));
+        }
+
+        let data = u7_slice(data)?;
+        let channel = Channel(status & 0x0F);
+        let event = match (status & 0xF0, data) {
+            (NOTE_OFF, &[note, velocity]) => MidiEvent::NoteOff {
+                channel,
+                note: U7(note),
+                velocity: U7(velocity),
+            },
+            (NOTE_ON, &[note, velocity]) => MidiEvent::NoteOn {
+                channel,
+                note: U7(note),
+                velocity: U7(velocity),
+            },
+            (POLY_AFTERTOUCH, &[note, pressure]) => MidiEvent::PolyAftertouch {
+                channel,
+                note: U7(note),
+                pressure: U7(pressure),
+            },
+            (CONTROL_CHANGE, &[controller, value]) => MidiEvent::ControlChange {
+                channel,
+                controller: U7(controller),
+                value: U7(value),
+            },
+            (PROGRAM_CHANGE, &[program]) => MidiEvent::ProgramChange {
+                channel,
+                program: U7(program),
+            },
+            (CHANNEL_AFTERTOUCH, &[pressure]) => MidiEvent::ChannelAftertouch {
+                channel,
+                pressure: U7(pressure),
+            },
+            (PITCH_BEND, &[lsb, msb]) => MidiEvent::PitchBend {
+                channel,
+                value: U14(u16::from(msb) << 7 | u16::from(lsb)),
+            },
+            (0xF0, &[]) => match status {
+                CLOCK => MidiEvent::Clock,
+                START => MidiEvent::Start,
+                CONTINUE => MidiEvent::Continue,
+                STOP => MidiEvent::Stop,
+                _ => return Err(DecodeError::UnknownStatus(status)),
+            },
+            (NOTE_OFF..=PITCH_BEND, _) => return Err(DecodeError::Length(raw.len())),
+            (0xF0, _) if matches!(status, CLOCK | START | CONTINUE | STOP) => {
+                return Err(DecodeError::Length(raw.len()))
+            }
+            _ => return Err(DecodeError::UnknownStatus(status)),
+        };
+        Ok(event)
+    }
+}
+
+/// Check that every byte is a 7-bit value
+fn u7_slice(data: &[u8]) -> Result<&[u8], DecodeError> {
+    match data.iter().find(|b| **b & 0x80 != 0) {
+        Some(b) => Err(DecodeError::OutOfRange(*b)),
+        None => Ok(data),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(event: &MidiEvent) -> Raw {
+        event.data()
+    }
+
+    fn decode(bytes: &[u8]) -> Result<MidiEvent, DecodeError> {
+        <MidiEvent as Decode>::decode(Raw::from_slice(bytes).unwrap())
+    }
+
+    #[test]
+    fn round_trip() {
+        let channel = Channel::new(9).unwrap();
+        let (a, b) = (U7::new(60).unwrap(), U7::new(127).unwrap());
+        let sysex = SysEx::new(SysExFragment::Start, &[0x41, 0x10, 0x42]).unwrap();
+        let events = [
+            (
+                MidiEvent::NoteOff {
+                    channel,
+                    note: a,
+                    velocity: b,
+                },
+                &[0x89, 60, 127][..],
+            ),
+            (
+                MidiEvent::NoteOn {
+                    channel,
+                    note: a,
+                    velocity: b,
+                },
+                &[0x99, 60, 127],
+            ),
+            (
+                MidiEvent::PolyAftertouch {
+                    channel,
+                    note: a,
+                    pressure: b,
+                },
+                &[0xA9, 60, 127],
+            ),
+            (
+                MidiEvent::ControlChange {
+                    channel,
+                    controller: a,
+                    value: b,
+                },
+                &[0xB9, 60, 127],
+            ),
+            (
+                MidiEvent::ProgramChange {
+                    channel,
+                    program: a,
+                },
+                &[0xC9, 60],
+            ),
+            (
+                MidiEvent::ChannelAftertouch {
+                    channel,
+                    pressure: b,
+                },
+                &[0xD9, 127],
+            ),
+            (
+                MidiEvent::PitchBend {
+                    channel,
+                    value: U14::new(0x3FFF).unwrap(),
+                },
+                &[0xE9, 0x7F, 0x7F],
+            ),
+            (
+                MidiEvent::PitchBend {
+                    channel,
+                    value: U14::CENTRE,
+                },
+                &[0xE9, 0x00, 0x40],
+            ),
+            (MidiEvent::SysEx(sysex), &[0xF0, 0x01, 0x41, 0x10, 0x42]),
+            (MidiEvent::Clock, &[0xF8]),
+            (MidiEvent::Start, &[0xFA]),
+            (MidiEvent::Continue, &[0xFB]),
+            (MidiEvent::Stop, &[0xFC]),
+        ];
+        for (event, bytes) in &events {
+            assert_eq!(&encode(event)[..], *bytes, "{:?}", event);
+            assert_eq!(decode(bytes).as_ref(), Ok(event));
+        }
+    }
+
+    #[test]
+    fn sysex_fragments() {
+        let message: h::Vec<u8, 70> = (0..70).collect();
+        let fragments: h::Vec<SysEx, 3> = SysEx::fragments(&message).unwrap().collect();
+        let kinds: h::Vec<SysExFragment, 3> = fragments.iter().map(SysEx::fragment).collect();
+        assert_eq!(
+            kinds,
+            [
+                SysExFragment::Start,
+                SysExFragment::Continue,
+                SysExFragment::End
+            ]
+        );
+        let lens: h::Vec<usize, 3> = fragments.iter().map(|f| f.data().len()).collect();
+        assert_eq!(lens, [SYSEX_FRAGMENT_LEN, SYSEX_FRAGMENT_LEN, 10]);
+        for f in &fragments {
+            let event = MidiEvent::SysEx(f.clone());
+            assert_eq!(decode(&encode(&event)), Ok(event));
+        }
+
+        // A short or empty message is sent whole
+        let mut short = SysEx::fragments(&[]).unwrap();
+        assert_eq!(short.next().unwrap().fragment(), SysExFragment::Complete);
+        assert!(short.next().is_none());
+
+        assert!(SysEx::fragments(&[0x80]).is_none());
+        assert!(SysEx::new(SysExFragment::Complete, &[0x80]).is_none());
+        assert!(SysEx::new(SysExFragment::Complete, &message[..SYSEX_FRAGMENT_LEN + 1]).is_none());
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(Channel::new(15).map(Channel::get), Some(15));
+        assert!(Channel::new(16).is_none());
+        assert_eq!(U7::new(127).map(U7::get), Some(127));
+        assert!(U7::new(128).is_none());
+        assert_eq!(U14::new(0x3FFF).map(U14::get), Some(0x3FFF));
+        assert!(U14::new(0x4000).is_none());
+    }
+
+    #[test]
+    fn decode_errors() {
+        assert_eq!(decode(&[]), Err(DecodeError::Length(0)));
+        assert_eq!(decode(&[0x90, 60]), Err(DecodeError::Length(2)));
+        assert_eq!(decode(&[0x90, 60, 1, 2]), Err(DecodeError::Length(4)));
+        assert_eq!(decode(&[0xC0]), Err(DecodeError::Length(1)));
+        assert_eq!(decode(&[0xF8, 0]), Err(DecodeError::Length(2)));
+        assert_eq!(decode(&[0xF0]), Err(DecodeError::Length(1)));
+
+        assert_eq!(decode(&[0xF1]), Err(DecodeError::UnknownStatus(0xF1)));
+        assert_eq!(decode(&[0xFF]), Err(DecodeError::UnknownStatus(0xFF)));
+        assert_eq!(decode(&[0x40, 1, 2]), Err(DecodeError::UnknownStatus(0x40)));
+
+        assert_eq!(decode(&[0x90, 0x80, 1]), Err(DecodeError::OutOfRange(0x80)));
+        assert_eq!(
+            decode(&[0xF0, 0x00, 0xF7]),
+            Err(DecodeError::OutOfRange(0xF7))
+        );
+        assert_eq!(
+            decode(&[0xF0, 0x04]),
+            Err(DecodeError::UnknownFragment(0x04))
+        );
+    }
+}
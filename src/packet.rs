@@ -1 +1,591 @@
+//! Packets, their headers, and the traits for converting payloads to and from raw bytes.
 
+use core::convert::{Infallible, TryFrom};
+
+use bitflags::bitflags;
+use heapless as h;
+
+use crate::command;
+use crate::io::SerialWrite;
+use crate::Error;
+
+/// Packet containing data of type `D`. In general, D should implement Encode and Decode.
+///
+/// `N` is the largest payload which can be sent on the link, and must be the same at both
+/// ends. It defaults to `PACKET_LEN`, and can be at most 255 as the length is a single byte in
+/// the header; larger values fail to compile. The payloads defined in this crate need `N` to be
+/// at least `PACKET_LEN`, and fail to compile when encoded into smaller packets.
+///
+/// `Packet::new`, `command`, `response` and `midi` create packets of the default capacity.
+/// Their `_sized` counterparts create packets of any capacity, given by the type, e.g.
+/// `Packet::<_, 64>::command_sized`.
+#[derive(Clone, Debug)]
+pub struct Packet<D, const N: usize = PACKET_LEN> {
+    pub(crate) typ: PacketType,
+    pub(crate) flags: Flags,
+    pub(crate) target: Addr,
+    pub(crate) source: Addr,
+    pub(crate) seq: u8,
+    pub(crate) data: D,
+}
+
+bitflags! {
+    /// Header flags. The remaining bits are reserved, and packets with any of them set are
+    /// rejected with `Error::InvalidFlags`.
+    pub struct Flags: u8 {
+        /// The packet should not be handled by its target, but is still forwarded along a daisy
+        /// chain, e.g. so that it can be seen by a sniffer
+        const IGNORE = 0b00000001;
+        /// The receiver should acknowledge the packet, as set by `reliable::Channel`
+        const ACK_REQUESTED = 0b00000010;
+        /// The packet answers an earlier one, e.g. it carries a `command::Response`
+        const IS_RESPONSE = 0b00000100;
+        /// The payload is part of a larger message
+        const IS_FRAGMENT = 0b00001000;
+        /// Further fragments of the message follow this one. Only valid with `IS_FRAGMENT`.
+        const MORE_FRAGMENTS = 0b00010000;
+    }
+}
+
+#[repr(transparent)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Addr(pub(crate) u8);
+
+pub const CONTROLLER: Addr = Addr(0);
+pub const BROADCAST: Addr = Addr(255);
+
+impl Addr {
+    /// Create a module address. Returns `None` for the reserved `CONTROLLER` and `BROADCAST`
+    /// addresses, which must be used by name instead.
+    pub fn new(addr: u8) -> Option<Self> {
+        match Addr(addr) {
+            CONTROLLER | BROADCAST => None,
+            a => Some(a),
+        }
+    }
+
+    /// The address as it appears on the wire
+    pub fn get(self) -> u8 {
+        self.0
+    }
+
+    pub fn is_controller(self) -> bool {
+        self == CONTROLLER
+    }
+
+    pub fn is_broadcast(self) -> bool {
+        self == BROADCAST
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum PacketType {
+    Command = 0x01,
+    MidiEvent = 0x02,
+    Ack = 0x03,
+    Nak = 0x04,
+
+    Raw = 0xFF, // not really useful on its own
+}
+
+impl TryFrom<u8> for PacketType {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x01 => Ok(PacketType::Command),
+            0x02 => Ok(PacketType::MidiEvent),
+            0x03 => Ok(PacketType::Ack),
+            0x04 => Ok(PacketType::Nak),
+            0xFF => Ok(PacketType::Raw),
+            _ => Err(Error::UnknownPacketType(value)),
+        }
+    }
+}
+
+/// By default each packet contains up to 32 data bytes, preceded by a length byte in the
+/// header.
+pub const PACKET_LEN: usize = 32;
+
+/// Longest payload which the length byte in the header can describe
+const MAX_PAYLOAD_LEN: usize = u8::MAX as usize;
+
+/// Packet capacity `N`, as seen by the payloads defined in this crate
+pub(crate) struct Capacity<const N: usize>;
+
+impl<const N: usize> Capacity<N> {
+    /// Fails to compile if `N` is too small for the payloads defined in this crate, so that
+    /// they are never truncated
+    pub(crate) const FITS_PAYLOADS: () = assert!(N >= PACKET_LEN, "packet capacity is under 32");
+}
+
+pub type Raw<const N: usize = PACKET_LEN> = h::Vec<u8, N>;
+
+/// A packet which borrows its payload, e.g. from a receive buffer, as returned by
+/// `Packet::parse`
+pub type PacketRef<'a, const N: usize = PACKET_LEN> = Packet<&'a [u8], N>;
+
+/// Largest number of header bytes preceding the payload, for any `Version`
+pub(crate) const MAX_HEADER_LEN: usize = 7;
+
+/// Combined with the version number to give the leading byte of `Version::V3` and later
+/// headers. The values this gives are not used for any `PacketType`.
+const VERSION_MARKER: u8 = 0xA0;
+
+/// Layout of the packet header. Both ends of a link must use the same version, which can be
+/// agreed with `Command::Hello`.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
+#[repr(u8)]
+pub enum Version {
+    /// The original layout: type, flags, target, sequence number and payload length. There is
+    /// no source address, so packets read with this layout have `BROADCAST` as their source.
+    V1 = 1,
+    /// Adds the source address after the target
+    V2 = 2,
+    /// Adds a leading byte holding the version number, `0xA0` plus the number, so that packets
+    /// from incompatible firmware are rejected with `Error::IncompatibleVersion` rather than
+    /// misread
+    #[default]
+    V3 = 3,
+}
+
+impl Version {
+    /// The newest version supported by this crate
+    pub const LATEST: Version = Version::V3;
+
+    /// Convert from the version number sent in `Command::Hello`
+    pub fn from_u8(version: u8) -> Option<Self> {
+        match version {
+            1 => Some(Version::V1),
+            2 => Some(Version::V2),
+            3 => Some(Version::V3),
+            _ => None,
+        }
+    }
+
+    /// The newest version supported by both ends of a link, given the newest version
+    /// supported by the peer. Returns `None` if the peer only supports unknown versions.
+    pub fn negotiate(peer: u8) -> Option<Self> {
+        Self::from_u8(peer.min(Self::LATEST as u8))
+    }
+
+    /// Number of header bytes preceding the payload
+    pub(crate) fn header_len(self) -> usize {
+        match self {
+            Version::V1 => 5,
+            Version::V2 => 6,
+            Version::V3 => 7,
+        }
+    }
+
+    /// The leading byte of the header, for versions which have one
+    fn marker(self) -> Option<u8> {
+        match self {
+            Version::V1 | Version::V2 => None,
+            v => Some(VERSION_MARKER | v as u8),
+        }
+    }
+
+    /// The layout of a header received by a node expecting this version, given its first byte.
+    ///
+    /// A version byte other than the expected one is rejected, whichever version the receiver
+    /// expects. Headers with no version byte are read as `V1` by receivers expecting one, so
+    /// that `Command::Hello` is understood. Anything else sent with them is then rejected by
+    /// `check_fallback`.
+    pub(crate) fn detect(self, first: u8) -> Result<Version, Error> {
+        match self.marker() {
+            Some(marker) if first == marker => Ok(self),
+            _ if first & 0xF0 == VERSION_MARKER => Err(Error::IncompatibleVersion(first & 0x0F)),
+            None => Ok(self),
+            Some(_) => Ok(Version::V1),
+        }
+    }
+
+    /// Check a packet read with the `layout` given by `detect`. Packets with no version byte
+    /// are only accepted by a receiver expecting one if they carry `Command::Hello` or its
+    /// response, and are otherwise reported as version 1, as V1 and V2 headers can't be told
+    /// apart. `opcode` is the first byte of the payload, or `None` if it hasn't arrived yet.
+    pub(crate) fn check_fallback(
+        self,
+        layout: Version,
+        typ: PacketType,
+        len: usize,
+        opcode: Option<u8>,
+    ) -> Result<(), Error> {
+        let hello = typ == PacketType::Command && len > 0 && opcode.is_none_or(command::is_hello);
+        if layout == self || hello {
+            Ok(())
+        } else {
+            Err(Error::IncompatibleVersion(Version::V1 as u8))
+        }
+    }
+}
+
+pub trait Encode<const N: usize = PACKET_LEN> {
+    fn data(&self) -> Raw<N>;
+}
+
+pub trait Decode<const N: usize = PACKET_LEN>: Sized {
+    type Error;
+    fn decode(raw: Raw<N>) -> Result<Self, Self::Error>;
+}
+
+impl<const N: usize> Encode<N> for Raw<N> {
+    fn data(&self) -> Raw<N> {
+        self.clone()
+    }
+}
+
+impl<const N: usize> Decode<N> for Raw<N> {
+    type Error = Infallible;
+    fn decode(raw: Raw<N>) -> Result<Self, Self::Error> {
+        Ok(raw)
+    }
+}
+
+impl<D> Packet<D> {
+    /// Create a packet of the given type, with no flags set. The source is `CONTROLLER` until
+    /// changed with `with_source`.
+    pub fn new(typ: PacketType, target: Addr, data: D) -> Self {
+        Self::new_sized(typ, target, data)
+    }
+
+    /// Create a command packet
+    pub fn command(target: Addr, data: D) -> Self {
+        Self::command_sized(target, data)
+    }
+
+    /// Create a command packet answering an earlier one, with `Flags::IS_RESPONSE` set
+    pub fn response(target: Addr, data: D) -> Self {
+        Self::response_sized(target, data)
+    }
+
+    /// Create a MIDI event packet
+    pub fn midi(target: Addr, event: D) -> Self {
+        Self::midi_sized(target, event)
+    }
+}
+
+impl<D, const N: usize> Packet<D, N> {
+    /// Fails to compile if `N` is too large for the length byte in the header
+    const CAPACITY_FITS: () = assert!(N <= MAX_PAYLOAD_LEN, "packet capacity is over 255");
+
+    /// As `Packet::new`, for packets of any capacity
+    pub fn new_sized(typ: PacketType, target: Addr, data: D) -> Self {
+        let () = Self::CAPACITY_FITS;
+        Packet {
+            typ,
+            flags: Flags::empty(),
+            target,
+            source: CONTROLLER,
+            seq: 0,
+            data,
+        }
+    }
+
+    /// As `Packet::command`, for packets of any capacity
+    pub fn command_sized(target: Addr, data: D) -> Self {
+        Self::new_sized(PacketType::Command, target, data)
+    }
+
+    /// As `Packet::response`, for packets of any capacity
+    pub fn response_sized(target: Addr, data: D) -> Self {
+        Self::command_sized(target, data).with_flags(Flags::IS_RESPONSE)
+    }
+
+    /// As `Packet::midi`, for packets of any capacity
+    pub fn midi_sized(target: Addr, event: D) -> Self {
+        Self::new_sized(PacketType::MidiEvent, target, event)
+    }
+
+    /// Replace the packet's flags
+    pub fn with_flags(mut self, flags: Flags) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// Replace the packet's source address
+    pub fn with_source(mut self, source: Addr) -> Self {
+        self.source = source;
+        self
+    }
+
+    pub fn with_data<F>(&self, data: F) -> Packet<F, N> {
+        Packet {
+            typ: self.typ,
+            flags: self.flags,
+            target: self.target,
+            source: self.source,
+            seq: self.seq,
+            data,
+        }
+    }
+
+    pub fn packet_type(&self) -> PacketType {
+        self.typ
+    }
+
+    pub fn flags(&self) -> Flags {
+        self.flags
+    }
+
+    pub fn target(&self) -> Addr {
+        self.target
+    }
+
+    /// Address of the node which sent the packet. `BROADCAST` if it is not known, e.g. because
+    /// the packet was read with `Version::V1`.
+    pub fn source(&self) -> Addr {
+        self.source
+    }
+
+    /// Sequence number assigned by a `reliable::Channel`, or 0 if the packet is unsequenced
+    pub fn seq(&self) -> u8 {
+        self.seq
+    }
+
+    pub fn data(&self) -> &D {
+        &self.data
+    }
+
+    pub fn into_data(self) -> D {
+        self.data
+    }
+}
+
+impl<D, const N: usize> Packet<D, N>
+where
+    D: Encode<N> + Decode<N>,
+{
+    pub fn write<S: SerialWrite>(&self, s: S) -> Result<(), Error<S::Error>> {
+        self.encoded().write_raw(s)
+    }
+
+    pub fn encoded(&self) -> Packet<Raw<N>, N> {
+        let d = self.data.data();
+        self.with_data(d)
+    }
+}
+
+impl<D: AsRef<[u8]>, const N: usize> Packet<D, N> {
+    /// The header bytes which precede the payload on the wire. Fails if the payload is longer
+    /// than `N`, or too long for the length byte.
+    pub(crate) fn header(&self, version: Version) -> Result<h::Vec<u8, MAX_HEADER_LEN>, Error> {
+        let () = Self::CAPACITY_FITS;
+        let len = self.data.as_ref().len();
+        if len > N {
+            return Err(Error::PayloadTooLong(len));
+        }
+        let mut header = h::Vec::new();
+        // The header is never longer than MAX_HEADER_LEN
+        if let Some(marker) = version.marker() {
+            let _ = header.push(marker);
+        }
+        let _ = header.extend_from_slice(&[self.typ as u8, self.flags.bits(), self.target.0]);
+        if version >= Version::V2 {
+            let _ = header.push(self.source.0);
+        }
+        let _ = header.extend_from_slice(&[self.seq, len as u8]);
+        Ok(header)
+    }
+}
+
+impl<const N: usize> Packet<Raw<N>, N> {
+    /// Decode the payload of a received packet
+    pub fn decoded<D: Decode<N>>(&self) -> Result<Packet<D, N>, D::Error> {
+        let data = D::decode(self.data.clone())?;
+        Ok(self.with_data(data))
+    }
+
+    /// Parse a header of `version.header_len()` bytes, returning a packet with no data and the
+    /// length of the payload which follows the header
+    pub(crate) fn from_header(header: &[u8], version: Version) -> Result<(Self, usize), Error> {
+        let () = Self::CAPACITY_FITS;
+        let header = match (version.marker(), header.split_first()) {
+            (None, _) => header,
+            (Some(_), Some((&first, rest))) => match version.detect(first)? {
+                v if v == version => rest,
+                // Only reached if `detect` wasn't called first
+                _ => return Err(Error::IncompatibleVersion(Version::V1 as u8)),
+            },
+            (Some(_), None) => return Err(Error::Framing),
+        };
+        let (&typ, rest) = header.split_first().ok_or(Error::Framing)?;
+        let typ = PacketType::try_from(typ)?;
+        let (source, rest) = match (version, rest) {
+            (Version::V1, &[flags, target, seq, len]) => (BROADCAST, [flags, target, seq, len]),
+            (Version::V2 | Version::V3, &[flags, target, source, seq, len]) => {
+                (Addr(source), [flags, target, seq, len])
+            }
+            _ => return Err(Error::Framing),
+        };
+        let [flags, target, seq, len] = rest;
+        let flags = match Flags::from_bits(flags) {
+            Some(f) if f.contains(Flags::MORE_FRAGMENTS) && !f.contains(Flags::IS_FRAGMENT) => {
+                return Err(Error::InvalidFlags(flags))
+            }
+            Some(f) => f,
+            None => return Err(Error::InvalidFlags(flags)),
+        };
+        let len = len as usize;
+        if len > N {
+            return Err(Error::PayloadTooLong(len));
+        }
+        let packet = Packet {
+            typ,
+            flags,
+            target: Addr(target),
+            source,
+            seq,
+            data: h::Vec::new(),
+        };
+
+        Ok((packet, len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::{Capabilities, Command, Features, PacketTypes, Response};
+    use crate::sim::{self, MockSerial};
+    use crate::{PacketDecoder, WireFormat};
+
+    fn read(serial: &MockSerial, version: Version) -> Result<Packet<Raw>, Error<sim::Empty>> {
+        Packet::read_raw_as(serial, WireFormat::Legacy, version)
+    }
+
+    fn decode(bytes: &[u8], version: Version) -> nb::Result<Packet<Raw>, Error> {
+        let mut decoder = PacketDecoder::with_format(WireFormat::Legacy).with_version(version);
+        decoder.feed_slice(bytes).1
+    }
+
+    fn bytes(packet: &Packet<Raw>, version: Version) -> std::vec::Vec<u8> {
+        let serial = MockSerial::new();
+        packet
+            .write_raw_as(&serial, WireFormat::Legacy, version)
+            .unwrap();
+        serial.take()
+    }
+
+    #[test]
+    fn builders() {
+        // The capacity is inferred as the default
+        let packet = Packet::command(Addr(3), Command::Ping).encoded();
+        assert_eq!(packet.data().capacity(), PACKET_LEN);
+        let response = Packet::response(CONTROLLER, Response::Pong);
+        assert_eq!(response.flags(), Flags::IS_RESPONSE);
+        assert_eq!(response.source(), CONTROLLER);
+
+        let packet = Packet::<_, 255>::command_sized(Addr(3), Command::Ping).encoded();
+        assert_eq!(packet.data().capacity(), 255);
+        let midi = Packet::<_, 64>::midi_sized(BROADCAST, ());
+        assert_eq!(midi.packet_type(), PacketType::MidiEvent);
+    }
+
+    #[test]
+    fn version_byte() {
+        let packet = Packet::new(PacketType::Ack, Addr(1), Raw::new());
+        assert_eq!(bytes(&packet, Version::V3)[0], 0xA3);
+        assert_eq!(bytes(&packet, Version::V2)[0], PacketType::Ack as u8);
+        assert_eq!(bytes(&packet, Version::V1)[0], PacketType::Ack as u8);
+    }
+
+    #[test]
+    fn incompatible_versions() {
+        // A V2 packet's type byte must not be mistaken for a version byte
+        let ack = Packet::new(PacketType::Ack, Addr(1), Raw::new()).with_source(Addr(2));
+        let v2 = bytes(&ack, Version::V2);
+        let serial = MockSerial::new();
+        serial.push(&v2);
+        assert_eq!(
+            read(&serial, Version::V3).unwrap_err(),
+            Error::IncompatibleVersion(1)
+        );
+        assert!(matches!(
+            decode(&v2, Version::V3),
+            Err(nb::Error::Other(Error::IncompatibleVersion(1)))
+        ));
+        assert_eq!(
+            Packet::<&[u8]>::parse_as(&v2, WireFormat::Legacy, Version::V3).unwrap_err(),
+            Error::IncompatibleVersion(1)
+        );
+
+        // Nor is any other command sent with V1 headers accepted
+        let ping = Packet::command(Addr(1), Command::Ping).encoded();
+        assert!(matches!(
+            decode(&bytes(&ping, Version::V1), Version::V3),
+            Err(nb::Error::Other(Error::IncompatibleVersion(1)))
+        ));
+
+        // A V2 receiver recognises the version byte of a V3 packet
+        let v3 = bytes(&ack, Version::V3);
+        let serial = MockSerial::new();
+        serial.push(&v3);
+        assert_eq!(
+            read(&serial, Version::V2).unwrap_err(),
+            Error::IncompatibleVersion(3)
+        );
+        assert!(matches!(
+            decode(&v3, Version::V2),
+            Err(nb::Error::Other(Error::IncompatibleVersion(3)))
+        ));
+        assert_eq!(
+            Packet::<&[u8]>::parse_as(&v3, WireFormat::Legacy, Version::V1).unwrap_err(),
+            Error::IncompatibleVersion(3)
+        );
+
+        // A later version is reported as such
+        let mut v4 = bytes(&ack, Version::V3);
+        v4[0] = 0xA4;
+        assert!(matches!(
+            decode(&v4, Version::V3),
+            Err(nb::Error::Other(Error::IncompatibleVersion(4)))
+        ));
+    }
+
+    #[test]
+    fn hello_handshake() {
+        // Both sides start out expecting the latest version, but the handshake is sent with V1
+        // headers so that older modules understand it
+        let serial = MockSerial::new();
+        let hello = Command::Hello {
+            version: Version::LATEST as u8,
+        };
+        let packet = Packet::command(Addr(1), hello).encoded();
+        packet
+            .write_raw_as(&serial, WireFormat::Legacy, Version::V1)
+            .unwrap();
+        let received = read(&serial, Version::LATEST).unwrap();
+        assert_eq!(received.decoded::<Command>().unwrap().into_data(), hello);
+        assert!(decode(&bytes(&packet, Version::V1), Version::LATEST).is_ok());
+
+        let reply = Response::Hello {
+            version: Version::V2 as u8,
+            capabilities: Capabilities {
+                packet_types: PacketTypes::COMMAND | PacketTypes::ACK,
+                max_payload: PACKET_LEN as u8,
+                features: Features::RELIABLE,
+            },
+        };
+        let packet = Packet::response(CONTROLLER, reply).encoded();
+        let v1 = bytes(&packet, Version::V1);
+        let (received, len) =
+            Packet::<&[u8]>::parse_as(&v1, WireFormat::Legacy, Version::LATEST).unwrap();
+        assert_eq!(len, v1.len());
+        assert_eq!(received.flags(), Flags::IS_RESPONSE);
+        assert_eq!(
+            Version::negotiate(reply_version(received.data())),
+            Some(Version::V2)
+        );
+    }
+
+    fn reply_version(payload: &[u8]) -> u8 {
+        let raw: Raw = Raw::from_slice(payload).unwrap();
+        match Response::decode(raw).unwrap() {
+            Response::Hello { version, .. } => version,
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+}
@@ -0,0 +1,389 @@
+//! Reliable delivery on top of `Packet`.
+//!
+//! A `Channel` gives each outgoing packet a sequence number and retransmits it until the peer
+//! acknowledges it, or the configured number of retries has been used up. On the receiving
+//! side, it acknowledges packets with `Flags::ACK_REQUESTED` set and drops duplicates caused by
+//! retransmission.
+//!
+//! The channel does no I/O itself. Packets received from the link are passed to
+//! `Channel::receive`, and anything returned by `Channel::poll_transmit` should be written to
+//! the link, e.g. with `Packet::write_raw` or a `PacketEncoder`.
+
+use crate::{Addr, Error, Flags, Packet, PacketType, Raw, BROADCAST, PACKET_LEN};
+
+/// Source of time for retransmission timeouts
+pub trait Clock {
+    /// Current time in milliseconds. May wrap around.
+    fn now_ms(&self) -> u32;
+}
+
+impl<F: Fn() -> u32> Clock for F {
+    fn now_ms(&self) -> u32 {
+        self()
+    }
+}
+
+/// Retransmission settings
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    /// Number of times a packet is retransmitted before giving up
+    pub retries: u8,
+    /// Time to wait for an acknowledgement before retransmitting
+    pub timeout_ms: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            retries: 3,
+            timeout_ms: 50,
+        }
+    }
+}
+
+/// Stop-and-wait reliable channel to a single peer, for packets of up to `N` bytes
+pub struct Channel<C, const N: usize = PACKET_LEN> {
+    peer: Addr,
+    clock: C,
+    config: Config,
+    next_seq: u8,
+    pending: Option<Pending<N>>,
+    last_received: Option<u8>,
+    reply: Option<Packet<Raw<N>, N>>,
+}
+
+/// A packet waiting to be acknowledged
+struct Pending<const N: usize> {
+    packet: Packet<Raw<N>, N>,
+    sent_at: Option<u32>,
+    attempts: u8,
+}
+
+impl<C: Clock, const N: usize> Channel<C, N> {
+    /// Create a channel to `peer`, which is where acknowledgements are sent
+    pub fn new(peer: Addr, clock: C, config: Config) -> Self {
+        Channel {
+            peer,
+            clock,
+            config,
+            next_seq: 1,
+            pending: None,
+            last_received: None,
+            reply: None,
+        }
+    }
+
+    /// Queue a packet for reliable delivery, assigning it the next sequence number and setting
+    /// `Flags::ACK_REQUESTED`. Returns `WouldBlock` if the previous packet has not been
+    /// acknowledged yet.
+    pub fn send(&mut self, mut packet: Packet<Raw<N>, N>) -> nb::Result<(), Error> {
+        if self.pending.is_some() {
+            return Err(nb::Error::WouldBlock);
+        }
+        packet.seq = self.next_seq;
+        packet.flags |= Flags::ACK_REQUESTED;
+        self.next_seq = next_seq(self.next_seq);
+        self.pending = Some(Pending {
+            packet,
+            sent_at: None,
+            attempts: 0,
+        });
+        Ok(())
+    }
+
+    /// Returns true once the last packet sent has been acknowledged, or given up on
+    pub fn is_idle(&self) -> bool {
+        self.pending.is_none()
+    }
+
+    /// Returns the next packet to write to the link, if any. This is either an
+    /// acknowledgement, or a queued packet which is due to be (re)transmitted.
+    ///
+    /// Returns `Error::Timeout` once, when the queued packet has been retransmitted
+    /// `Config::retries` times without being acknowledged. The packet is then dropped.
+    pub fn poll_transmit(&mut self) -> Result<Option<Packet<Raw<N>, N>>, Error> {
+        if let Some(reply) = self.reply.take() {
+            return Ok(Some(reply));
+        }
+
+        let now = self.clock.now_ms();
+        let pending = match &mut self.pending {
+            Some(pending) => pending,
+            None => return Ok(None),
+        };
+        match pending.sent_at {
+            Some(t) if now.wrapping_sub(t) < self.config.timeout_ms => Ok(None),
+            _ if pending.attempts > self.config.retries => {
+                self.pending = None;
+                Err(Error::Timeout)
+            }
+            _ => {
+                pending.sent_at = Some(now);
+                pending.attempts += 1;
+                Ok(Some(pending.packet.clone()))
+            }
+        }
+    }
+
+    /// Handle a packet received from the link. Acknowledgements are consumed, and packets which
+    /// request it are acknowledged. Returns the packet if it should be passed on to the
+    /// application, i.e. it is not a control packet or a duplicate.
+    ///
+    /// Packets with `Flags::IGNORE` set are dropped without being acknowledged or acted on. Any
+    /// forwarding should be done first, e.g. with `endpoint::Endpoint::route`.
+    ///
+    /// Packets from nodes other than the peer are ignored, so several channels can share a
+    /// link, each passing on and acknowledging only its own peer's packets. Those with a
+    /// `BROADCAST` source are accepted, as `Version::V1` headers carry no source.
+    pub fn receive(&mut self, packet: Packet<Raw<N>, N>) -> Option<Packet<Raw<N>, N>> {
+        let from_peer = packet.source == self.peer || packet.source.is_broadcast();
+        match packet.typ {
+            _ if packet.flags.contains(Flags::IGNORE) || !from_peer => None,
+            PacketType::Ack => {
+                if self.is_pending(packet.seq) {
+                    self.pending = None;
+                }
+                None
+            }
+            PacketType::Nak => {
+                if let Some(pending) = &mut self.pending {
+                    if pending.packet.seq == packet.seq {
+                        pending.sent_at = None;
+                    }
+                }
+                None
+            }
+            _ if packet.seq == 0 || !packet.flags.contains(Flags::ACK_REQUESTED) => Some(packet),
+            _ => {
+                let mut ack = self.control(PacketType::Ack, packet.seq);
+                // Acknowledge from the address the packet was sent to, unless it was broadcast
+                if !packet.target.is_broadcast() {
+                    ack.source = packet.target;
+                }
+                self.reply = Some(ack);
+                if self.last_received == Some(packet.seq) {
+                    return None;
+                }
+                self.last_received = Some(packet.seq);
+                Some(packet)
+            }
+        }
+    }
+
+    /// Report that a corrupted packet was received, so the peer retransmits without waiting
+    /// for its timeout to expire.
+    pub fn reject(&mut self) {
+        let expected = self.last_received.map_or(1, next_seq);
+        self.reply = Some(self.control(PacketType::Nak, expected));
+    }
+
+    fn is_pending(&self, seq: u8) -> bool {
+        matches!(&self.pending, Some(p) if p.packet.seq == seq)
+    }
+
+    /// An ACK or NAK to the peer. The source is `BROADCAST`, meaning unknown, unless it is set
+    /// afterwards.
+    fn control(&self, typ: PacketType, seq: u8) -> Packet<Raw<N>, N> {
+        let mut packet = Packet::new_sized(typ, self.peer, Raw::new()).with_source(BROADCAST);
+        packet.seq = seq;
+        packet
+    }
+}
+
+/// Sequence numbers run from 1 to 255, as 0 marks an unsequenced packet
+fn next_seq(seq: u8) -> u8 {
+    seq.checked_add(1).unwrap_or(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::Cell;
+
+    use super::*;
+    use crate::CONTROLLER;
+
+    fn packet(target: Addr, byte: u8) -> Packet<Raw> {
+        Packet::command(target, Raw::from_slice(&[byte]).unwrap())
+    }
+
+    fn ack(from: Addr, seq: u8) -> Packet<Raw> {
+        let mut ack = Packet::new(PacketType::Ack, CONTROLLER, Raw::new()).with_source(from);
+        ack.seq = seq;
+        ack
+    }
+
+    const CONFIG: Config = Config {
+        retries: 2,
+        timeout_ms: 10,
+    };
+
+    #[test]
+    fn retry_and_timeout() {
+        let now = Cell::new(0);
+        let mut channel = Channel::new(Addr(1), || now.get(), CONFIG);
+        channel.send(packet(Addr(1), 7)).unwrap();
+        assert!(channel.send(packet(Addr(1), 8)).is_err());
+
+        let sent = channel.poll_transmit().unwrap().unwrap();
+        assert_eq!(sent.seq(), 1);
+        assert!(sent.flags().contains(Flags::ACK_REQUESTED));
+        assert!(channel.poll_transmit().unwrap().is_none());
+
+        // Retransmitted after each timeout, `retries` times
+        for _ in 0..CONFIG.retries {
+            now.set(now.get() + 9);
+            assert!(channel.poll_transmit().unwrap().is_none());
+            now.set(now.get() + 1);
+            let resent = channel.poll_transmit().unwrap().unwrap();
+            assert_eq!(resent.seq(), 1);
+            assert_eq!(resent.data(), sent.data());
+        }
+
+        now.set(now.get() + 10);
+        assert_eq!(channel.poll_transmit().unwrap_err(), Error::Timeout);
+        assert!(channel.is_idle());
+        assert!(channel.poll_transmit().unwrap().is_none());
+
+        // The next packet gets the next sequence number
+        channel.send(packet(Addr(1), 8)).unwrap();
+        assert_eq!(channel.poll_transmit().unwrap().unwrap().seq(), 2);
+    }
+
+    #[test]
+    fn acknowledged() {
+        let now = Cell::new(0);
+        let mut channel = Channel::new(Addr(1), || now.get(), CONFIG);
+        channel.send(packet(Addr(1), 7)).unwrap();
+        channel.poll_transmit().unwrap();
+
+        // Wrong sequence number
+        assert!(channel.receive(ack(Addr(1), 2)).is_none());
+        assert!(!channel.is_idle());
+        assert!(channel.receive(ack(Addr(1), 1)).is_none());
+        assert!(channel.is_idle());
+        now.set(100);
+        assert!(channel.poll_transmit().unwrap().is_none());
+    }
+
+    #[test]
+    fn nak_retransmits_early() {
+        let now = Cell::new(0);
+        let mut channel = Channel::new(Addr(1), || now.get(), CONFIG);
+        channel.send(packet(Addr(1), 7)).unwrap();
+        channel.poll_transmit().unwrap();
+        let mut nak = ack(Addr(1), 1);
+        nak.typ = PacketType::Nak;
+        assert!(channel.receive(nak).is_none());
+        assert_eq!(channel.poll_transmit().unwrap().unwrap().seq(), 1);
+    }
+
+    #[test]
+    fn acknowledgements_from_other_peers() {
+        // Two channels on a controller, with the same sequence numbers in flight
+        let now = Cell::new(0);
+        let mut one = Channel::new(Addr(1), || now.get(), CONFIG);
+        let mut two = Channel::new(Addr(2), || now.get(), CONFIG);
+        one.send(packet(Addr(1), 1)).unwrap();
+        two.send(packet(Addr(2), 2)).unwrap();
+        one.poll_transmit().unwrap();
+        two.poll_transmit().unwrap();
+
+        // Each sees the other's acknowledgement, and ignores it
+        assert!(one.receive(ack(Addr(2), 1)).is_none());
+        assert!(two.receive(ack(Addr(2), 1)).is_none());
+        assert!(!one.is_idle());
+        assert!(two.is_idle());
+
+        // Acknowledgements with no source, from V1 headers, are accepted
+        assert!(one.receive(ack(BROADCAST, 1)).is_none());
+        assert!(one.is_idle());
+    }
+
+    #[test]
+    fn packets_from_other_peers() {
+        // A controller with a channel to each of two modules, which both send sequence number 1
+        let now = Cell::new(0);
+        let mut one = Channel::new(Addr(1), || now.get(), CONFIG);
+        let mut two = Channel::new(Addr(2), || now.get(), CONFIG);
+        let mut module_one = Channel::new(CONTROLLER, || now.get(), CONFIG);
+        let mut module_two = Channel::new(CONTROLLER, || now.get(), CONFIG);
+        module_one
+            .send(packet(CONTROLLER, 1).with_source(Addr(1)))
+            .unwrap();
+        module_two
+            .send(packet(CONTROLLER, 2).with_source(Addr(2)))
+            .unwrap();
+        // Module one's packet is lost
+        module_one.poll_transmit().unwrap();
+        let sent = module_two.poll_transmit().unwrap().unwrap();
+
+        // Only the channel to module two passes it on and acknowledges it
+        assert!(one.receive(sent.clone()).is_none());
+        assert!(one.poll_transmit().unwrap().is_none());
+        assert!(two.receive(sent).is_some());
+        let ack = two.poll_transmit().unwrap().unwrap();
+        assert_eq!(ack.target(), Addr(2));
+
+        // Module one only receives packets addressed to it, so its packet is still pending
+        assert!(module_two.receive(ack).is_none());
+        assert!(module_two.is_idle());
+        assert!(!module_one.is_idle());
+    }
+
+    #[test]
+    fn duplicates() {
+        let now = Cell::new(0);
+        let mut channel = Channel::new(CONTROLLER, || now.get(), CONFIG);
+        let mut received = packet(Addr(3), 7);
+        received.seq = 5;
+        received.flags = Flags::ACK_REQUESTED;
+
+        assert!(channel.receive(received.clone()).is_some());
+        let reply = channel.poll_transmit().unwrap().unwrap();
+        assert_eq!(reply.packet_type(), PacketType::Ack);
+        assert_eq!(reply.seq(), 5);
+        assert_eq!(reply.target(), CONTROLLER);
+        assert_eq!(reply.source(), Addr(3));
+
+        // A retransmission is acknowledged again, but not passed on
+        assert!(channel.receive(received.clone()).is_none());
+        assert_eq!(channel.poll_transmit().unwrap().unwrap().seq(), 5);
+
+        received.seq = 6;
+        assert!(channel.receive(received).is_some());
+
+        // Unsequenced packets are passed on without an acknowledgement
+        assert!(channel.receive(packet(Addr(3), 8)).is_some());
+        assert!(channel.receive(packet(Addr(3), 8)).is_some());
+        channel.poll_transmit().unwrap();
+        assert!(channel.poll_transmit().unwrap().is_none());
+    }
+
+    #[test]
+    fn ignored() {
+        let now = Cell::new(0);
+        let mut channel = Channel::new(CONTROLLER, || now.get(), CONFIG);
+        let mut received = packet(Addr(3), 7);
+        received.seq = 5;
+        received.flags = Flags::ACK_REQUESTED | Flags::IGNORE;
+        assert!(channel.receive(received.clone()).is_none());
+        assert!(channel.poll_transmit().unwrap().is_none());
+
+        // It isn't taken for a duplicate once it is sent again without the flag
+        received.flags = Flags::ACK_REQUESTED;
+        assert!(channel.receive(received).is_some());
+
+        // Nor are acknowledgements acted on
+        channel.send(packet(CONTROLLER, 1)).unwrap();
+        channel.poll_transmit().unwrap();
+        let ignored = ack(CONTROLLER, 1).with_flags(Flags::IGNORE);
+        assert!(channel.receive(ignored).is_none());
+        assert!(!channel.is_idle());
+    }
+
+    #[test]
+    fn sequence_numbers_skip_zero() {
+        assert_eq!(next_seq(1), 2);
+        assert_eq!(next_seq(255), 1);
+    }
+}